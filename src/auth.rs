@@ -0,0 +1,315 @@
+use crate::handshake::Handshake;
+use crate::short_text::ShortText;
+use crate::utils::{strerr, DecoderUtils, EncoderUtils};
+use async_std::fs;
+use async_std::io::{Read, Result, Write};
+use async_std::path::Path;
+use async_std::prelude::*;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use rand::random;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+pub const AUTH_PROTOCOL_VERSION: usize = 2;
+
+#[async_trait]
+pub trait Authenticator: Sync + Send {
+    fn method(&self) -> &'static [u8];
+    async fn authenticate(
+        &self,
+        reader: &mut (dyn Read + Unpin + Send),
+        writer: &mut (dyn Write + Unpin + Send),
+    ) -> Result<Option<String>>;
+}
+
+async fn load_pairs(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path).await?;
+    let mut ret = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, char::is_whitespace);
+        match (parts.next(), parts.next().map(str::trim)) {
+            (Some(identity), Some(secret)) if !secret.is_empty() && identity.len() < 256 => {
+                ret.push((identity.to_owned(), secret.to_owned()))
+            }
+            _ => return strerr(format!("malformed line in {}: {}", path.display(), line)),
+        }
+    }
+    Ok(ret)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub struct TokenFile {
+    tokens: Vec<(String, Vec<u8>)>,
+}
+
+impl TokenFile {
+    pub async fn load(path: &Path) -> Result<TokenFile> {
+        let tokens = load_pairs(path)
+            .await?
+            .into_iter()
+            .map(|(identity, token)| (identity, token.into_bytes()))
+            .collect();
+        Ok(TokenFile { tokens })
+    }
+}
+
+#[async_trait]
+impl Authenticator for TokenFile {
+    fn method(&self) -> &'static [u8] {
+        b"token"
+    }
+
+    async fn authenticate(
+        &self,
+        mut reader: &mut (dyn Read + Unpin + Send),
+        _writer: &mut (dyn Write + Unpin + Send),
+    ) -> Result<Option<String>> {
+        let token = reader.decode_binary().await?;
+        let mut ret = None;
+        for (identity, expected) in &self.tokens {
+            if constant_time_eq(expected, &token) {
+                ret = Some(identity.to_owned());
+            }
+        }
+        Ok(ret)
+    }
+}
+
+pub struct SharedSecret {
+    secrets: HashMap<String, Vec<u8>>,
+}
+
+impl SharedSecret {
+    pub async fn load(path: &Path) -> Result<SharedSecret> {
+        let secrets = load_pairs(path)
+            .await?
+            .into_iter()
+            .map(|(identity, secret)| (identity, secret.into_bytes()))
+            .collect();
+        Ok(SharedSecret { secrets })
+    }
+
+    fn verify(&self, identity: &str, nonce: &[u8], digest: &[u8]) -> bool {
+        match self.secrets.get(identity) {
+            Some(secret) => {
+                let mut mac = Hmac::<Sha256>::new_varkey(secret).unwrap();
+                mac.update(nonce);
+                mac.verify(digest).is_ok()
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for SharedSecret {
+    fn method(&self) -> &'static [u8] {
+        b"challenge"
+    }
+
+    async fn authenticate(
+        &self,
+        mut reader: &mut (dyn Read + Unpin + Send),
+        mut writer: &mut (dyn Write + Unpin + Send),
+    ) -> Result<Option<String>> {
+        let nonce: [u8; 32] = random();
+        writer.encode_binary(&nonce).await?;
+        writer.flush().await?;
+        let identity = reader.decode_short_text().await?;
+        let digest = reader.decode_binary().await?;
+        if self.verify(identity.as_ref(), &nonce, &digest) {
+            return Ok(Some(identity.to_string()));
+        }
+        Ok(None)
+    }
+}
+
+static mut INSTANCE: Option<Box<dyn Authenticator>> = None;
+
+pub fn init(auth: Option<Box<dyn Authenticator>>) {
+    unsafe {
+        INSTANCE = auth;
+    }
+}
+
+fn get_authenticator() -> Option<&'static dyn Authenticator> {
+    unsafe { INSTANCE.as_deref() }
+}
+
+pub async fn authenticate<
+    Reader: Read + Unpin + Send,
+    Writer: Write + Unpin + Send,
+>(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    protocol: &Handshake,
+) -> Result<Option<String>> {
+    authenticate_with(get_authenticator(), reader, writer, protocol).await
+}
+
+async fn authenticate_with<Reader: Read + Unpin + Send, Writer: Write + Unpin + Send>(
+    auth: Option<&dyn Authenticator>,
+    reader: &mut Reader,
+    writer: &mut Writer,
+    protocol: &Handshake,
+) -> Result<Option<String>> {
+    if protocol.version < AUTH_PROTOCOL_VERSION {
+        return match auth {
+            Some(_) => strerr("authentication required"),
+            None => Ok(None),
+        };
+    }
+    let auth = match auth {
+        Some(auth) => auth,
+        None => {
+            writer.encode_short_text(&ShortText::build(b"none")).await?;
+            writer.flush().await?;
+            return Ok(None);
+        }
+    };
+    writer
+        .encode_short_text(&ShortText::build(auth.method()))
+        .await?;
+    writer.flush().await?;
+    match auth.authenticate(reader, writer).await? {
+        Some(identity) => {
+            writer.write_all(b"OK").await?;
+            writer
+                .encode_short_text(&ShortText::build(identity.as_bytes()))
+                .await?;
+            writer.flush().await?;
+            Ok(Some(identity))
+        }
+        None => {
+            writer.write_all(b"NO").await?;
+            writer.encode_binary(b"authentication failed").await?;
+            writer.flush().await?;
+            strerr("authentication failed")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::{Capabilities, PROTOCOL_VERSION};
+
+    fn tokens() -> TokenFile {
+        TokenFile {
+            tokens: vec![
+                ("alice".to_owned(), b"alice-token".to_vec()),
+                ("bob".to_owned(), b"bob-token".to_vec()),
+            ],
+        }
+    }
+
+    fn secrets() -> SharedSecret {
+        let mut secrets = HashMap::new();
+        secrets.insert("alice".to_owned(), b"alice-secret".to_vec());
+        SharedSecret { secrets }
+    }
+
+    fn protocol(version: usize) -> Handshake {
+        Handshake {
+            version,
+            caps: Capabilities::NONE,
+        }
+    }
+
+    async fn binary(data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.encode_binary(data).await.unwrap();
+        buf
+    }
+
+    #[async_std::test]
+    async fn token_matching() {
+        let auth = tokens();
+        let mut output = Vec::new();
+        let input = binary(b"bob-token").await;
+        let ret = auth.authenticate(&mut input.as_slice(), &mut output).await;
+        assert_eq!(ret.unwrap(), Some("bob".to_owned()));
+        for token in &[&b"bob-toke"[..], b"bob-token!", b""] {
+            let input = binary(token).await;
+            let ret = auth.authenticate(&mut input.as_slice(), &mut output).await;
+            assert_eq!(ret.unwrap(), None);
+        }
+    }
+
+    #[async_std::test]
+    async fn challenge_verification() {
+        let auth = secrets();
+        let nonce = [7u8; 32];
+        let mut mac = Hmac::<Sha256>::new_varkey(b"alice-secret").unwrap();
+        mac.update(&nonce);
+        let digest = mac.finalize().into_bytes();
+        assert!(auth.verify("alice", &nonce, &digest));
+        assert!(!auth.verify("alice", &[8u8; 32], &digest));
+        assert!(!auth.verify("alice", &nonce, &digest[..16]));
+        assert!(!auth.verify("mallory", &nonce, &digest));
+
+        // the nonce is fresh, so a replayed digest is rejected over the wire
+        let mut input = Vec::new();
+        let identity = ShortText::build(b"alice");
+        input.encode_short_text(&identity).await.unwrap();
+        input.encode_binary(&digest).await.unwrap();
+        let mut output = Vec::new();
+        let ret = auth.authenticate(&mut input.as_slice(), &mut output).await;
+        assert_eq!(ret.unwrap(), None);
+        assert_eq!(output[0], 32);
+        assert_eq!(output.len(), 33);
+    }
+
+    #[async_std::test]
+    async fn authenticate_accepts_and_rejects() {
+        let auth = tokens();
+        let mut output = Vec::new();
+        let input = binary(b"alice-token").await;
+        let ret = authenticate_with(
+            Some(&auth),
+            &mut input.as_slice(),
+            &mut output,
+            &protocol(PROTOCOL_VERSION),
+        )
+        .await;
+        assert_eq!(ret.unwrap(), Some("alice".to_owned()));
+        assert_eq!(output, b"\x05tokenOK\x05alice");
+
+        let mut output = Vec::new();
+        let input = binary(b"wrong").await;
+        let ret = authenticate_with(
+            Some(&auth),
+            &mut input.as_slice(),
+            &mut output,
+            &protocol(PROTOCOL_VERSION),
+        )
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(&output[..8], b"\x05tokenNO");
+    }
+
+    #[async_std::test]
+    async fn older_versions() {
+        let auth = tokens();
+        let mut output = Vec::new();
+        let ret = authenticate_with(Some(&auth), &mut &b""[..], &mut output, &protocol(1)).await;
+        assert!(ret.is_err());
+        assert!(output.is_empty());
+
+        let ret = authenticate_with(None, &mut &b""[..], &mut output, &protocol(1)).await;
+        assert_eq!(ret.unwrap(), None);
+        assert!(output.is_empty());
+
+        let ret = authenticate_with(None, &mut &b""[..], &mut output, &protocol(2)).await;
+        assert_eq!(ret.unwrap(), None);
+        assert_eq!(output, b"\x04none");
+    }
+}
@@ -8,43 +8,72 @@ use weak_table::PtrWeakHashSet;
 
 #[async_trait]
 pub trait NotifyReceiver: Send + Sync {
-    async fn on_notify(&self, key: &EventKey, data: Option<&[u8]>);
+    async fn on_notify(&self, sub: &EventKey, key: &EventKey, data: Option<&[u8]>);
 }
 
 #[async_trait]
 pub trait EventReceiver: Send + Sync {
-    async fn on_event(&self, key: &EventKey, data: Option<&[u8]>);
+    async fn on_event(&self, sub: &EventKey, key: &EventKey, data: Option<&[u8]>);
 }
 
 #[async_trait]
 pub trait GeneralReceiver: Send + Sync {
-    async fn receive(&self, key: &EventKey, data: Option<&[u8]>);
+    async fn receive(&self, sub: &EventKey, key: &EventKey, data: Option<&[u8]>);
 }
 
 #[async_trait]
 impl GeneralReceiver for dyn NotifyReceiver {
-    async fn receive(&self, key: &EventKey, data: Option<&[u8]>) {
-        self.on_notify(key, data).await
+    async fn receive(&self, sub: &EventKey, key: &EventKey, data: Option<&[u8]>) {
+        self.on_notify(sub, key, data).await
     }
 }
 
 #[async_trait]
 impl GeneralReceiver for dyn EventReceiver {
-    async fn receive(&self, key: &EventKey, data: Option<&[u8]>) {
-        self.on_event(key, data).await
+    async fn receive(&self, sub: &EventKey, key: &EventKey, data: Option<&[u8]>) {
+        self.on_event(sub, key, data).await
     }
 }
 
 #[async_trait]
 pub trait AlternativeReceiver: Send + Sync {
-    async fn receive(&self, data: Option<&[u8]>) -> bool;
+    async fn receive(&self, key: &EventKey, data: Option<&[u8]>) -> bool;
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct EventKey(pub ShortText, pub ShortText);
 
+fn match_part(pattern: &str, text: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => text.starts_with(prefix),
+        None => pattern == text,
+    }
+}
+
+impl EventKey {
+    pub fn is_pattern(&self) -> bool {
+        self.0.ends_with('*') || self.1.ends_with('*')
+    }
+
+    pub fn matches(&self, key: &EventKey) -> bool {
+        match_part(&self.0, &key.0) && match_part(&self.1, &key.1)
+    }
+}
+
+pub type Snapshot = Vec<(EventKey, Option<Vec<u8>>)>;
+
+type ReceiverMap<Receiver> = HashMap<EventKey, PtrWeakHashSet<Weak<Receiver>>>;
+
+fn remove_expired<Receiver: ?Sized>(map: &mut ReceiverMap<Receiver>) {
+    map.retain(|_, set| {
+        set.remove_expired();
+        !set.is_empty()
+    });
+}
+
 pub struct Broker<Receiver: GeneralReceiver + ?Sized + Send + Sync> {
-    map: Mutex<HashMap<EventKey, PtrWeakHashSet<Weak<Receiver>>>>,
+    map: Mutex<ReceiverMap<Receiver>>,
+    pattern_map: Mutex<ReceiverMap<Receiver>>,
     alt_map: Mutex<MultiMap<EventKey, Box<dyn AlternativeReceiver>>>,
 }
 
@@ -68,6 +97,7 @@ impl<Receiver: GeneralReceiver + ?Sized + Send + Sync> Broker<Receiver> {
     fn new() -> Self {
         Broker {
             map: Mutex::new(HashMap::new()),
+            pattern_map: Mutex::new(HashMap::new()),
             alt_map: Mutex::new(MultiMap::new()),
         }
     }
@@ -77,15 +107,27 @@ impl<Receiver: GeneralReceiver + ?Sized + Send + Sync> Broker<Receiver> {
         let map = self.map.lock().await;
         if let Some(set) = map.get(&key) {
             for item in set {
-                item.receive(&key, data).await;
+                item.receive(&key, &key, data).await;
             }
         }
         drop(map);
+        let pattern_map = self.pattern_map.lock().await;
+        for (pattern, set) in pattern_map.iter() {
+            if pattern.matches(&key) {
+                for item in set {
+                    item.receive(pattern, &key, data).await;
+                }
+            }
+        }
+        drop(pattern_map);
         let mut alt_map = self.alt_map.lock().await;
-        if let Some(list) = alt_map.get_vec_mut(&key) {
+        for (pattern, list) in alt_map.iter_all_mut() {
+            if !pattern.matches(&key) {
+                continue;
+            }
             let mut deleted = Vec::new();
             for (idx, item) in list.iter().enumerate() {
-                if !item.receive(data).await {
+                if !item.receive(&key, data).await {
                     deleted.push(idx);
                 }
             }
@@ -95,8 +137,16 @@ impl<Receiver: GeneralReceiver + ?Sized + Send + Sync> Broker<Receiver> {
         }
     }
 
+    fn select_map(&self, key: &EventKey) -> &Mutex<ReceiverMap<Receiver>> {
+        if key.is_pattern() {
+            &self.pattern_map
+        } else {
+            &self.map
+        }
+    }
+
     pub async fn register(&self, sender: Arc<Receiver>, key: EventKey) {
-        let mut guard = self.map.lock().await;
+        let mut guard = self.select_map(&key).lock().await;
         if let Some(set) = guard.get_mut(&key) {
             set.insert(sender);
         } else {
@@ -106,26 +156,70 @@ impl<Receiver: GeneralReceiver + ?Sized + Send + Sync> Broker<Receiver> {
         }
     }
 
+    pub async fn unregister(&self, sender: &Arc<Receiver>, key: &EventKey) {
+        let mut guard = self.select_map(key).lock().await;
+        if let Some(set) = guard.get_mut(key) {
+            set.remove(sender);
+            if set.is_empty() {
+                guard.remove(key);
+            }
+        }
+    }
+
     pub async fn alternative_register(&self, recv: Box<dyn AlternativeReceiver>, key: EventKey) {
         let mut guard = self.alt_map.lock().await;
         guard.insert(key, recv);
     }
 
     pub async fn cleanup(&self) {
-        let mut guard = self.map.lock().await;
-        let removed: Vec<_> = guard
-            .iter_mut()
-            .filter_map(|(k, set)| {
-                set.remove_expired();
-                if set.is_empty() {
-                    Some(k.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        for k in removed {
-            guard.remove(&k);
-        }
+        remove_expired(&mut *self.map.lock().await);
+        remove_expired(&mut *self.pattern_map.lock().await);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(entity: &str, key: &str) -> EventKey {
+        EventKey(entity.parse().unwrap(), key.parse().unwrap())
+    }
+
+    #[test]
+    fn exact_key() {
+        let pattern = key("shared", "a");
+        assert!(!pattern.is_pattern());
+        assert!(pattern.matches(&key("shared", "a")));
+        assert!(!pattern.matches(&key("shared", "ab")));
+        assert!(!pattern.matches(&key("other", "a")));
+    }
+
+    #[test]
+    fn prefix_key() {
+        let pattern = key("shared", "user.*");
+        assert!(pattern.is_pattern());
+        assert!(pattern.matches(&key("shared", "user.")));
+        assert!(pattern.matches(&key("shared", "user.alice")));
+        assert!(!pattern.matches(&key("shared", "user")));
+        assert!(!pattern.matches(&key("shared2", "user.alice")));
+    }
+
+    #[test]
+    fn wildcard_entity() {
+        let pattern = key("*", "status");
+        assert!(pattern.is_pattern());
+        assert!(pattern.matches(&key("a", "status")));
+        assert!(pattern.matches(&key("b", "status")));
+        assert!(!pattern.matches(&key("a", "status2")));
+        assert!(key("svc.*", "*").matches(&key("svc.1", "x")));
+        assert!(!key("svc.*", "*").matches(&key("svc", "x")));
+    }
+
+    #[test]
+    fn star_only_at_end() {
+        let pattern = key("shared", "a*b");
+        assert!(!pattern.is_pattern());
+        assert!(pattern.matches(&key("shared", "a*b")));
+        assert!(!pattern.matches(&key("shared", "axb")));
     }
 }
@@ -0,0 +1,327 @@
+use crate::error::{BusError, BusResult};
+use crate::short_text::ShortText;
+use crate::utils::{strerr, DecoderUtils};
+use async_std::io::Result;
+use serde_json::{json, Value};
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schema {
+    Any,
+    Empty,
+    Text,
+    Int,
+    Uint,
+    Bool,
+}
+
+impl FromStr for Schema {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "any" => Ok(Schema::Any),
+            "empty" => Ok(Schema::Empty),
+            "text" => Ok(Schema::Text),
+            "int" => Ok(Schema::Int),
+            "uint" => Ok(Schema::Uint),
+            "bool" => Ok(Schema::Bool),
+            _ => Err(format!("unknown schema: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Schema::Any => "any",
+            Schema::Empty => "empty",
+            Schema::Text => "text",
+            Schema::Int => "int",
+            Schema::Uint => "uint",
+            Schema::Bool => "bool",
+        })
+    }
+}
+
+impl Schema {
+    pub fn check(self, data: &[u8]) -> bool {
+        let text = std::str::from_utf8(data);
+        match self {
+            Schema::Any => true,
+            Schema::Empty => data.is_empty(),
+            Schema::Text => text.is_ok(),
+            Schema::Int => text.map(|x| x.trim().parse::<i64>().is_ok()).unwrap_or(false),
+            Schema::Uint => text.map(|x| x.trim().parse::<u64>().is_ok()).unwrap_or(false),
+            Schema::Bool => matches!(text, Ok("true") | Ok("false")),
+        }
+    }
+
+    fn openapi(self) -> Option<Value> {
+        match self {
+            Schema::Any => Some(json!({"type": "string", "format": "binary"})),
+            Schema::Empty => None,
+            Schema::Text => Some(json!({"type": "string"})),
+            Schema::Int => Some(json!({"type": "integer"})),
+            Schema::Uint => Some(json!({"type": "integer", "minimum": 0})),
+            Schema::Bool => Some(json!({"type": "boolean"})),
+        }
+    }
+
+    fn openapi_content(self) -> Value {
+        match self.openapi() {
+            Some(schema) => json!({"text/plain": {"schema": schema}}),
+            None => json!({}),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Method {
+    pub name: ShortText,
+    pub input: Schema,
+    pub output: Schema,
+}
+
+#[derive(Debug, Clone)]
+pub struct Descriptor {
+    pub version: ShortText,
+    pub description: String,
+    pub methods: Vec<Method>,
+    pub keys: Vec<ShortText>,
+}
+
+async fn decode_schema(payload: &mut &[u8]) -> Result<Schema> {
+    match payload.decode_short_text().await?.parse() {
+        Ok(schema) => Ok(schema),
+        Err(e) => strerr(e),
+    }
+}
+
+impl Descriptor {
+    pub async fn decode(mut payload: &[u8]) -> Result<Descriptor> {
+        let version = payload.decode_short_text().await?;
+        let description = match String::from_utf8(payload.decode_binary().await?) {
+            Ok(description) => description,
+            Err(_) => return strerr("invalid descriptor"),
+        };
+        let mut methods = Vec::new();
+        for _ in 0..payload.decode_varuint().await? {
+            methods.push(Method {
+                name: payload.decode_short_text().await?,
+                input: decode_schema(&mut payload).await?,
+                output: decode_schema(&mut payload).await?,
+            });
+        }
+        let mut keys = Vec::new();
+        for _ in 0..payload.decode_varuint().await? {
+            keys.push(payload.decode_short_text().await?);
+        }
+        if !payload.is_empty() {
+            return strerr("invalid descriptor");
+        }
+        Ok(Descriptor {
+            version,
+            description,
+            methods,
+            keys,
+        })
+    }
+
+    pub fn check_call(&self, method: &ShortText, data: &[u8]) -> BusResult<()> {
+        if self.methods.is_empty() {
+            return Ok(());
+        }
+        match self.methods.iter().find(|x| x.name == *method) {
+            Some(x) if x.input.check(data) => Ok(()),
+            Some(x) => Err(BusError::Malformed(format!(
+                "invalid argument: expected {}",
+                x.input
+            ))),
+            None => Err(BusError::NotFound("unknown method".to_owned())),
+        }
+    }
+
+    pub fn render(&self, name: &ShortText) -> String {
+        let mut ret = String::new();
+        let _ = writeln!(ret, "name: {}", name);
+        let _ = writeln!(ret, "version: {}", self.version);
+        let _ = writeln!(ret, "description: {}", self.description);
+        for method in &self.methods {
+            let _ = writeln!(
+                ret,
+                "method: {}({}) -> {}",
+                method.name, method.input, method.output
+            );
+        }
+        for key in &self.keys {
+            let _ = writeln!(ret, "key: {}", key);
+        }
+        ret
+    }
+}
+
+pub fn openapi(services: &[(ShortText, Descriptor)]) -> Value {
+    let mut paths = serde_json::Map::new();
+    for (name, descriptor) in services {
+        for method in &descriptor.methods {
+            let status = match method.output {
+                Schema::Empty => "204",
+                _ => "200",
+            };
+            let mut op = json!({
+                "tags": [name.to_string()],
+                "operationId": format!("{}.{}", name, method.name),
+                "responses": {
+                    "400": {"description": "invalid arguments"},
+                    "502": {"description": "call failed"},
+                    "504": {"description": "call timed out"},
+                },
+            });
+            op["responses"][status] = json!({
+                "description": "result",
+                "content": method.output.openapi_content(),
+            });
+            if method.input != Schema::Empty {
+                op["requestBody"] = json!({"content": method.input.openapi_content()});
+            }
+            paths.insert(
+                format!("/map/{}/{}", name, method.name),
+                json!({ "post": op }),
+            );
+        }
+        for key in &descriptor.keys {
+            paths.insert(
+                format!("/map/{}/{}", name, key),
+                json!({
+                    "get": {
+                        "tags": [name.to_string()],
+                        "responses": {
+                            "200": {"description": "current value"},
+                            "404": {"description": "no value"},
+                        },
+                    },
+                }),
+            );
+        }
+    }
+    let tags: Vec<_> = services
+        .iter()
+        .map(|(name, descriptor)| {
+            json!({
+                "name": name.to_string(),
+                "description": descriptor.description,
+                "x-version": descriptor.version.to_string(),
+            })
+        })
+        .collect();
+    json!({
+        "openapi": "3.0.0",
+        "info": {"title": "minibus", "version": env!("CARGO_PKG_VERSION")},
+        "tags": tags,
+        "paths": paths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::EncoderUtils;
+
+    async fn encode(methods: &[(&str, &str, &str)], keys: &[&str]) -> Vec<u8> {
+        let version = ShortText::build(b"1.0");
+        let mut buf = Vec::new();
+        buf.encode_short_text(&version).await.unwrap();
+        buf.encode_binary(b"test service").await.unwrap();
+        buf.encode_varuint(methods.len()).await.unwrap();
+        for (name, input, output) in methods {
+            for text in &[name, input, output] {
+                buf.encode_short_text(&text.parse().unwrap()).await.unwrap();
+            }
+        }
+        buf.encode_varuint(keys.len()).await.unwrap();
+        for key in keys {
+            buf.encode_short_text(&key.parse().unwrap()).await.unwrap();
+        }
+        buf
+    }
+
+    #[async_std::test]
+    async fn decode_descriptor() {
+        let payload = encode(&[("echo", "text", "text")], &["status"]).await;
+        let descriptor = Descriptor::decode(&payload).await.unwrap();
+        assert_eq!(descriptor.version, ShortText::build(b"1.0"));
+        assert_eq!(descriptor.description, "test service");
+        assert_eq!(descriptor.methods.len(), 1);
+        assert_eq!(descriptor.keys, vec![ShortText::build(b"status")]);
+        let rendered = descriptor.render(&ShortText::build(b"svc"));
+        assert!(rendered.starts_with("name: svc\nversion: 1.0\n"));
+        assert!(rendered.contains("key: status\n"));
+    }
+
+    #[test]
+    fn schema_check() {
+        assert!(Schema::Any.check(b"\xff"));
+        assert!(Schema::Empty.check(b""));
+        assert!(!Schema::Empty.check(b"x"));
+        assert!(Schema::Text.check("héllo".as_bytes()));
+        assert!(!Schema::Text.check(b"\xff"));
+        assert!(Schema::Int.check(b"-12"));
+        assert!(!Schema::Int.check(b"1.5"));
+        assert!(Schema::Uint.check(b" 7\n"));
+        assert!(!Schema::Uint.check(b"-7"));
+        assert!(Schema::Bool.check(b"true"));
+        assert!(!Schema::Bool.check(b"yes"));
+    }
+
+    #[test]
+    fn schema_names() {
+        for schema in &[
+            Schema::Any,
+            Schema::Empty,
+            Schema::Text,
+            Schema::Int,
+            Schema::Uint,
+            Schema::Bool,
+        ] {
+            assert_eq!(schema.to_string().parse::<Schema>(), Ok(*schema));
+        }
+        assert!("float".parse::<Schema>().is_err());
+    }
+
+    #[async_std::test]
+    async fn check_call_arguments() {
+        let payload = encode(&[("add", "int", "int"), ("ping", "empty", "empty")], &[]).await;
+        let descriptor = Descriptor::decode(&payload).await.unwrap();
+        let add = ShortText::build(b"add");
+        assert_eq!(descriptor.check_call(&add, b"3"), Ok(()));
+        assert!(matches!(
+            descriptor.check_call(&add, b"three"),
+            Err(BusError::Malformed(_))
+        ));
+        let ping = ShortText::build(b"ping");
+        assert_eq!(descriptor.check_call(&ping, b""), Ok(()));
+        assert!(matches!(
+            descriptor.check_call(&ShortText::build(b"nope"), b""),
+            Err(BusError::NotFound(_))
+        ));
+        let payload = encode(&[], &[]).await;
+        let descriptor = Descriptor::decode(&payload).await.unwrap();
+        assert_eq!(descriptor.check_call(&add, b"anything"), Ok(()));
+    }
+
+    #[async_std::test]
+    async fn reject_invalid_descriptor() {
+        let mut payload = encode(&[], &[]).await;
+        assert!(Descriptor::decode(&payload).await.is_ok());
+        payload.push(0);
+        assert!(Descriptor::decode(&payload).await.is_err());
+        assert!(Descriptor::decode(b"").await.is_err());
+        let version = ShortText::build(b"1");
+        let mut payload = Vec::new();
+        payload.encode_short_text(&version).await.unwrap();
+        payload.encode_binary(b"\xff\xfe").await.unwrap();
+        payload.extend_from_slice(b"\x00\x00");
+        assert!(Descriptor::decode(&payload).await.is_err());
+    }
+}
@@ -1,14 +1,24 @@
-use crate::broker::{get_event_broker, EventKey, EventReceiver, NotifyReceiver};
-use crate::packet::{Response, ResponsePayload};
+use crate::broker::{get_event_broker, EventKey, EventReceiver, NotifyReceiver, Snapshot};
+use crate::error::{BusError, BusResult};
+use crate::handshake::{Capabilities, Handshake};
+use crate::limits::get_limits;
+use crate::packet::{CodedResponse, EventKind, Response, ResponsePayload};
 use crate::short_text::ShortText;
-use crate::utils::{strerr, Decoder, DecoderUtils, Encoder, EncoderUtils, RandomKey};
+use crate::utils::{
+    deadline_after, is_expired, strerr, Decoder, DecoderUtils, Encoder, EncoderUtils, RandomKey,
+};
 use async_std::io::{Read, Result, Write};
 use async_std::prelude::*;
 use async_std::sync::{Arc, Mutex, Weak};
+use async_std::task;
 use async_trait::async_trait;
 use log::debug;
 use std::collections::{BTreeMap, HashMap};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
+// private: owner only; protected: others may read; public: others may read and write
 #[derive(PartialEq, Clone, Copy)]
 pub enum AccessTag {
     Private,
@@ -24,7 +34,7 @@ impl<T: Read + Unpin + Send> Decoder<AccessTag> for T {
             b"protected" => AccessTag::Protected,
             b"public" => AccessTag::Public,
             _ => {
-                return strerr("unexcepted");
+                return strerr("unexpected access tag");
             }
         };
         Ok(ret)
@@ -44,190 +54,473 @@ impl<T: Write + Unpin + Send> Encoder<AccessTag> for T {
     }
 }
 
-pub struct ValueWithAccess(Option<Vec<u8>>, AccessTag);
+fn take_subscription(
+    map: &mut HashMap<EventKey, u32>,
+    reqid: u32,
+    key: Option<EventKey>,
+) -> Option<(EventKey, u32)> {
+    let key = match key {
+        Some(key) => key,
+        None => map
+            .iter()
+            .find(|(_, id)| **id == reqid)
+            .map(|(key, _)| key.to_owned())?,
+    };
+    map.remove_entry(&key)
+}
+
+pub struct ValueWithAccess {
+    value: Option<Vec<u8>>,
+    access: AccessTag,
+    allow: Option<Vec<String>>,
+    deadline: Option<Instant>,
+    version: u64,
+}
+
+impl ValueWithAccess {
+    // the owner always passes; others need the access tag and, if set, the allowlist
+    fn permits(&self, owner: bool, identity: Option<&str>, write: bool) -> bool {
+        if owner {
+            return true;
+        }
+        let granted = match self.access {
+            AccessTag::Private => false,
+            AccessTag::Protected => !write,
+            AccessTag::Public => true,
+        };
+        match &self.allow {
+            Some(allow) => granted && matches!(identity, Some(x) if allow.iter().any(|y| y == x)),
+            None => granted,
+        }
+    }
+
+    fn current(&self) -> Option<&Vec<u8>> {
+        if is_expired(self.deadline, Instant::now()) {
+            None
+        } else {
+            self.value.as_ref()
+        }
+    }
+
+    fn current_version(&self) -> u64 {
+        if self.current().is_some() {
+            self.version
+        } else {
+            0
+        }
+    }
+}
+
+pub enum Condition {
+    Version(u64),
+    Value(Vec<u8>),
+}
+
+impl Condition {
+    pub fn check(&self, version: u64, value: Option<&Vec<u8>>) -> bool {
+        match self {
+            Condition::Version(expected) => *expected == version,
+            Condition::Value(expected) => value == Some(expected),
+        }
+    }
+}
+
+pub enum CasResult {
+    Applied(u64),
+    Mismatch(u64),
+}
+
+pub fn add_integer(value: Option<&Vec<u8>>, delta: i64) -> BusResult<i64> {
+    let current = match value {
+        Some(value) => match std::str::from_utf8(value)
+            .ok()
+            .and_then(|x| x.trim().parse::<i64>().ok())
+        {
+            Some(current) => current,
+            None => return Err(BusError::Malformed("not an integer".to_owned())),
+        },
+        None => 0,
+    };
+    match current.checked_add(delta) {
+        Some(ret) => Ok(ret),
+        None => Err(BusError::Malformed("integer overflow".to_owned())),
+    }
+}
 
 #[async_trait]
 pub trait Entity: Sync + Send {
-    async fn update_name(&self, _name: Option<&ShortText>) {}
+    fn identity(&self) -> Option<&str> {
+        None
+    }
+    // only a live connection can own names, anything else would leave them dangling
+    fn can_own_names(&self) -> bool {
+        false
+    }
+    async fn update_name(&self, _names: &[ShortText]) {}
+    async fn expire(&self, _now: Instant) {}
+    async fn restore(&self) -> BusResult<()> {
+        Ok(())
+    }
     async fn get(
         &self,
         sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
-    ) -> Result<Option<Vec<u8>>>;
+    ) -> BusResult<Option<Vec<u8>>>;
     async fn set(
         &self,
         sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
         val: Vec<u8>,
-    ) -> Result<()>;
-    async fn del(&self, sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> Result<()>;
-    async fn keys(&self, sender: Option<&Arc<dyn Entity>>) -> Result<Vec<(ShortText, AccessTag)>>;
+        ttl: Option<Duration>,
+    ) -> BusResult<()>;
+    async fn del(&self, sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> BusResult<()>;
+    async fn keys(
+        &self,
+        sender: Option<&Arc<dyn Entity>>,
+    ) -> BusResult<Vec<(ShortText, AccessTag)>>;
+    async fn get_versioned(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        _key: &ShortText,
+    ) -> BusResult<(u64, Option<Vec<u8>>)> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn compare_and_set(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        _key: &ShortText,
+        _cond: Condition,
+        _val: Vec<u8>,
+        _ttl: Option<Duration>,
+    ) -> BusResult<CasResult> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn increment(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        _key: &ShortText,
+        _delta: i64,
+    ) -> BusResult<i64> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    // the entity that actually serves a call, for entities that dispatch to others
+    async fn select_callee(&self) -> BusResult<Option<Arc<dyn Entity>>> {
+        Ok(None)
+    }
     async fn call(
         &self,
         _sender: &Arc<dyn EntityReceiver>,
         _reqid: u32,
         _key: &ShortText,
         _val: &[u8],
-    ) -> Result<()> {
-        strerr("not supported")
+        _timeout: Duration,
+    ) -> BusResult<()> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn cancel(&self, _resid: u32) {}
+    async fn pending_calls(&self) -> usize {
+        0
     }
 }
 
 #[async_trait]
 pub trait EntityReceiver: Sync + Send {
-    async fn assign_call_ids(&self, _reqid: u32, _resid: u32) {}
+    async fn assign_call_ids(&self, _reqid: u32, _resid: u32) -> BusResult<()> {
+        Ok(())
+    }
     async fn remove_call_id(&self, _resid: u32) {}
+    async fn call_next(&self, _reqid: u32, _data: Vec<u8>) {}
     async fn call_resp(&self, reqid: u32, val: ResponsePayload);
 }
 
+struct PendingCall {
+    reqid: u32,
+    callee: Option<Weak<dyn Entity>>,
+}
+
+struct CallRecord {
+    caller: Weak<dyn EntityReceiver>,
+    timeout: Duration,
+    deadline: Instant,
+}
+
 pub struct ExternalEntity<Writer: 'static + Write + Unpin + Send> {
-    name: Mutex<Option<ShortText>>,
+    protocol: Handshake,
+    identity: Option<String>,
+    names: Mutex<Vec<ShortText>>,
     writer: Mutex<Writer>,
     kvstore: Mutex<HashMap<ShortText, ValueWithAccess>>,
-    pending_call: Mutex<BTreeMap<u32, u32>>,
-    call_record: Mutex<BTreeMap<u32, Weak<dyn EntityReceiver>>>,
+    revision: AtomicU64,
+    pending_call: Mutex<BTreeMap<u32, PendingCall>>,
+    call_record: Mutex<BTreeMap<u32, CallRecord>>,
     notify_subscribe: Mutex<HashMap<EventKey, u32>>,
     event_subscribe: Mutex<HashMap<EventKey, u32>>,
+    backlog: Mutex<HashMap<u32, Snapshot>>,
 }
 
 #[async_trait]
 impl<Writer: 'static + Write + Unpin + Send> EntityReceiver for ExternalEntity<Writer> {
-    async fn assign_call_ids(&self, reqid: u32, resid: u32) {
+    async fn assign_call_ids(&self, reqid: u32, resid: u32) -> BusResult<()> {
         let mut guard = self.pending_call.lock().await;
-        guard.insert(resid, reqid);
+        if guard.len() >= get_limits().max_pending_calls {
+            return Err(BusError::Limit("too many pending calls".to_owned()));
+        }
+        guard.insert(
+            resid,
+            PendingCall {
+                reqid,
+                callee: None,
+            },
+        );
+        Ok(())
     }
     async fn remove_call_id(&self, resid: u32) {
         let mut guard = self.pending_call.lock().await;
         guard.remove(&resid);
     }
+    async fn call_next(&self, resid: u32, data: Vec<u8>) {
+        let guard = self.pending_call.lock().await;
+        if let Some(call) = guard.get(&resid) {
+            let _ = self
+                .send(Response::new_next(
+                    call.reqid,
+                    ResponsePayload::SuccessWithData(data),
+                ))
+                .await;
+        }
+    }
     async fn call_resp(&self, resid: u32, val: ResponsePayload) {
         let mut guard = self.pending_call.lock().await;
-        if let Some(reqid) = guard.remove(&resid) {
-            let _ = self.send(Response::new_resp(reqid, val)).await;
+        if let Some(call) = guard.remove(&resid) {
+            let _ = self.send(Response::new_resp(call.reqid, val)).await;
         }
     }
 }
 
 #[async_trait]
 impl<Writer: 'static + Write + Unpin + Send> NotifyReceiver for ExternalEntity<Writer> {
-    async fn on_notify(&self, key: &EventKey, data: Option<&[u8]>) {
+    async fn on_notify(&self, sub: &EventKey, key: &EventKey, data: Option<&[u8]>) {
         let guard = self.notify_subscribe.lock().await;
         debug!("notify({:?}): {:?}", key, data);
-        if let Some(id) = guard.get(key) {
-            let _ = self
-                .send(Response::new_next(
-                    *id,
-                    if let Some(data) = data {
-                        ResponsePayload::SuccessWithData(data.to_vec())
-                    } else {
-                        ResponsePayload::Success
-                    },
-                ))
-                .await;
+        if let Some(id) = guard.get(sub) {
+            let payload = self.build_next(EventKind::Notify, key, data).await;
+            let _ = self.send(Response::new_next(*id, payload)).await;
         }
     }
 }
 
 #[async_trait]
 impl<Writer: 'static + Write + Unpin + Send> EventReceiver for ExternalEntity<Writer> {
-    async fn on_event(&self, key: &EventKey, data: Option<&[u8]>) {
+    async fn on_event(&self, sub: &EventKey, key: &EventKey, data: Option<&[u8]>) {
         let guard = self.event_subscribe.lock().await;
         debug!("event({:?}): {:?}", key, data);
-        if let Some(id) = guard.get(key) {
-            let _ = self
-                .send(Response::new_next(
-                    *id,
-                    if let Some(data) = data {
-                        ResponsePayload::SuccessWithData(data.to_vec())
-                    } else {
-                        ResponsePayload::Success
-                    },
-                ))
-                .await;
+        if let Some(id) = guard.get(sub) {
+            let mut backlog = self.backlog.lock().await;
+            if let Some(list) = backlog.get_mut(id) {
+                list.push((key.to_owned(), data.map(|x| x.to_vec())));
+                return;
+            }
+            let payload = self.build_next(EventKind::of(data), key, data).await;
+            let _ = self.send(Response::new_next(*id, payload)).await;
         }
     }
 }
 
 #[async_trait]
 impl<Writer: 'static + Write + Unpin + Send> Entity for ExternalEntity<Writer> {
-    async fn update_name(&self, name: Option<&ShortText>) {
-        let mut guard = self.name.lock().await;
-        *guard = name.map(|x| x.to_owned());
+    fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    fn can_own_names(&self) -> bool {
+        true
+    }
+
+    async fn update_name(&self, names: &[ShortText]) {
+        let mut guard = self.names.lock().await;
+        *guard = names.to_vec();
+    }
+
+    async fn expire(&self, now: Instant) {
+        let mut guard = self.kvstore.lock().await;
+        for (key, va) in guard.iter_mut() {
+            if va.value.is_none() || !is_expired(va.deadline, now) {
+                continue;
+            }
+            self.emit(key, None).await;
+            va.value.take();
+            va.deadline.take();
+        }
+        drop(guard);
+        let expired: Vec<_> = {
+            let mut guard = self.call_record.lock().await;
+            let ids: Vec<_> = guard
+                .iter()
+                .filter(|(_, record)| record.deadline <= now)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| guard.remove(&id).map(|record| (id, record)))
+                .collect()
+        };
+        for (id, record) in expired {
+            if let Some(caller) = record.caller.upgrade() {
+                task::spawn(async move {
+                    let e = BusError::Timeout("timeout".to_owned());
+                    caller.call_resp(id, ResponsePayload::Failed(e)).await;
+                });
+            }
+            let _ = self
+                .send(Response::new_cancel(id, ResponsePayload::Success))
+                .await;
+        }
     }
 
     async fn get(
         &self,
-        _sender: Option<&Arc<dyn Entity>>,
+        sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
-    ) -> Result<Option<Vec<u8>>> {
+    ) -> BusResult<Option<Vec<u8>>> {
         let guard = self.kvstore.lock().await;
         match guard.get(key) {
-            Some(ValueWithAccess(_, AccessTag::Private)) => strerr("not allowed"),
-            Some(ValueWithAccess(value, _)) => Ok(value.to_owned()),
-            None => strerr("not found"),
+            Some(va) if !self.permits(va, sender, false) => {
+                Err(BusError::Forbidden("not allowed".to_owned()))
+            }
+            Some(va) => Ok(va.current().cloned()),
+            None => Err(BusError::NotFound("not found".to_owned())),
         }
     }
     async fn set(
         &self,
-        _sender: Option<&Arc<dyn Entity>>,
+        sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
         val: Vec<u8>,
-    ) -> Result<()> {
+        ttl: Option<Duration>,
+    ) -> BusResult<()> {
+        let deadline = deadline_after(ttl)?;
         let mut guard = self.kvstore.lock().await;
         match guard.get_mut(key) {
-            Some(ValueWithAccess(value, AccessTag::Public)) => {
-                if let Some(name) = self.name.lock().await.to_owned() {
-                    get_event_broker()
-                        .send(EventKey(name, key.to_owned()), Some(&val[..]))
-                        .await;
-                }
-                value.replace(val);
+            Some(va) if self.permits(va, sender, true) => {
+                self.store(key, va, val, deadline).await;
                 Ok(())
             }
-            Some(_) => strerr("not allowned"),
-            None => strerr("not found"),
+            Some(_) => Err(BusError::Forbidden("not allowed".to_owned())),
+            None => Err(BusError::NotFound("not found".to_owned())),
         }
     }
-    async fn del(&self, _sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> Result<()> {
+    async fn del(&self, sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> BusResult<()> {
         let mut guard = self.kvstore.lock().await;
         match guard.get_mut(key) {
-            Some(ValueWithAccess(value, AccessTag::Public)) => {
-                if let Some(name) = self.name.lock().await.to_owned() {
-                    get_event_broker()
-                        .send(EventKey(name, key.to_owned()), None)
-                        .await;
-                }
-                value.take();
+            Some(va) if self.permits(va, sender, true) => {
+                self.emit(key, None).await;
+                va.value.take();
+                va.deadline.take();
                 Ok(())
             }
-            Some(_) => strerr("not allowned"),
-            None => strerr("not found"),
+            Some(_) => Err(BusError::Forbidden("not allowed".to_owned())),
+            None => Err(BusError::NotFound("not found".to_owned())),
         }
     }
 
-    async fn keys(&self, _sender: Option<&Arc<dyn Entity>>) -> Result<Vec<(ShortText, AccessTag)>> {
+    async fn keys(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+    ) -> BusResult<Vec<(ShortText, AccessTag)>> {
         let guard = self.kvstore.lock().await;
         let ret = guard
             .iter()
-            .map(|(key, ValueWithAccess(_, access))| (key.to_owned(), access.to_owned()))
+            .map(|(key, va)| (key.to_owned(), va.access))
             .collect();
         Ok(ret)
     }
 
+    async fn get_versioned(
+        &self,
+        sender: Option<&Arc<dyn Entity>>,
+        key: &ShortText,
+    ) -> BusResult<(u64, Option<Vec<u8>>)> {
+        let guard = self.kvstore.lock().await;
+        match guard.get(key) {
+            Some(va) if !self.permits(va, sender, false) => {
+                Err(BusError::Forbidden("not allowed".to_owned()))
+            }
+            Some(va) => Ok((va.current_version(), va.current().cloned())),
+            None => Err(BusError::NotFound("not found".to_owned())),
+        }
+    }
+
+    async fn compare_and_set(
+        &self,
+        sender: Option<&Arc<dyn Entity>>,
+        key: &ShortText,
+        cond: Condition,
+        val: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BusResult<CasResult> {
+        let deadline = deadline_after(ttl)?;
+        let mut guard = self.kvstore.lock().await;
+        match guard.get_mut(key) {
+            Some(va) if self.permits(va, sender, true) => {
+                if !cond.check(va.current_version(), va.current()) {
+                    return Ok(CasResult::Mismatch(va.current_version()));
+                }
+                self.store(key, va, val, deadline).await;
+                Ok(CasResult::Applied(va.version))
+            }
+            Some(_) => Err(BusError::Forbidden("not allowed".to_owned())),
+            None => Err(BusError::NotFound("not found".to_owned())),
+        }
+    }
+
+    async fn increment(
+        &self,
+        sender: Option<&Arc<dyn Entity>>,
+        key: &ShortText,
+        delta: i64,
+    ) -> BusResult<i64> {
+        let mut guard = self.kvstore.lock().await;
+        match guard.get_mut(key) {
+            Some(va) if self.permits(va, sender, true) => {
+                let ret = add_integer(va.current(), delta)?;
+                let deadline = va.current().and(va.deadline);
+                self.store(key, va, ret.to_string().into_bytes(), deadline)
+                    .await;
+                Ok(ret)
+            }
+            Some(_) => Err(BusError::Forbidden("not allowed".to_owned())),
+            None => Err(BusError::NotFound("not found".to_owned())),
+        }
+    }
+
     async fn call(
         &self,
         sender: &Arc<dyn EntityReceiver>,
         reqid: u32,
         key: &ShortText,
         val: &[u8],
-    ) -> Result<()> {
+        timeout: Duration,
+    ) -> BusResult<()> {
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => return Err(BusError::Malformed("timeout out of range".to_owned())),
+        };
         let mut guard = self.call_record.lock().await;
+        if guard.len() >= get_limits().max_pending_calls {
+            return Err(BusError::Limit("too many pending calls".to_owned()));
+        }
         let id = guard.gen_random_key();
         let mut buf = Vec::new();
         buf.encode_short_text(key).await?;
         buf.write(val).await?;
-        sender.assign_call_ids(reqid, id).await;
-        guard.insert(id, Arc::downgrade(sender));
+        sender.assign_call_ids(reqid, id).await?;
+        guard.insert(
+            id,
+            CallRecord {
+                caller: Arc::downgrade(sender),
+                timeout,
+                deadline,
+            },
+        );
         if let Err(e) = self
             .send(Response::new_call(
                 id,
@@ -235,98 +528,546 @@ impl<Writer: 'static + Write + Unpin + Send> Entity for ExternalEntity<Writer> {
             ))
             .await
         {
+            guard.remove(&id);
             sender.remove_call_id(id).await;
-            Err(e)
+            Err(e.into())
         } else {
             Ok(())
         }
     }
+
+    async fn pending_calls(&self) -> usize {
+        self.call_record.lock().await.len()
+    }
+
+    async fn cancel(&self, resid: u32) {
+        let mut guard = self.call_record.lock().await;
+        if guard.remove(&resid).is_some() {
+            let _ = self
+                .send(Response::new_cancel(resid, ResponsePayload::Success))
+                .await;
+        }
+    }
 }
 
 impl<Writer: 'static + Write + Unpin + Send> ExternalEntity<Writer> {
-    pub async fn get_name(&self) -> Option<ShortText> {
-        self.name.lock().await.to_owned()
+    pub fn protocol(&self) -> &Handshake {
+        &self.protocol
+    }
+
+    fn permits(&self, va: &ValueWithAccess, sender: Option<&Arc<dyn Entity>>, write: bool) -> bool {
+        let owner = matches!(sender,
+            Some(x) if ptr::eq(Arc::as_ptr(x) as *const u8, self as *const Self as *const u8));
+        va.permits(owner, sender.and_then(|x| x.identity()), write)
+    }
+
+    pub async fn get_names(&self) -> Vec<ShortText> {
+        self.names.lock().await.to_owned()
+    }
+
+    async fn emit(&self, key: &ShortText, data: Option<&[u8]>) {
+        let names = self.names.lock().await.to_owned();
+        for name in names {
+            get_event_broker()
+                .send(EventKey(name, key.to_owned()), data)
+                .await;
+        }
+    }
+
+    async fn build_next(
+        &self,
+        kind: EventKind,
+        key: &EventKey,
+        data: Option<&[u8]>,
+    ) -> ResponsePayload {
+        if self.protocol.supports(Capabilities::EXTENDED_NEXT) {
+            let mut buf = Vec::new();
+            buf.encode(kind).await.unwrap();
+            buf.encode_short_text(&key.0).await.unwrap();
+            buf.encode_short_text(&key.1).await.unwrap();
+            if let Some(data) = data {
+                buf.extend_from_slice(data);
+            }
+            ResponsePayload::SuccessWithData(buf)
+        } else if let Some(data) = data {
+            ResponsePayload::SuccessWithData(data.to_vec())
+        } else {
+            ResponsePayload::Success
+        }
+    }
+
+    async fn store(
+        &self,
+        key: &ShortText,
+        va: &mut ValueWithAccess,
+        val: Vec<u8>,
+        deadline: Option<Instant>,
+    ) {
+        self.emit(key, Some(&val[..])).await;
+        va.value.replace(val);
+        va.deadline = deadline;
+        va.version = self.revision.fetch_add(1, Ordering::Relaxed) + 1;
     }
 
     pub async fn send(&self, resp: Response) -> Result<()> {
         let mut guard = self.writer.lock().await;
-        guard.encode(resp).await?;
+        if self.protocol.supports(Capabilities::ERROR_CODES) {
+            guard.encode(CodedResponse(resp)).await?;
+        } else {
+            guard.encode(resp).await?;
+        }
         guard.flush().await?;
         Ok(())
     }
 
-    pub async fn set_acl(&self, key: &ShortText, acl: AccessTag) {
+    pub async fn set_acl(
+        &self,
+        key: &ShortText,
+        acl: AccessTag,
+        allow: Option<Vec<String>>,
+    ) -> BusResult<()> {
         let mut guard = self.kvstore.lock().await;
         match guard.get_mut(key) {
             Some(va) => {
-                let ValueWithAccess(_, ref mut access) = va;
-                *access = acl;
+                va.access = acl;
+                va.allow = allow;
             }
             None => {
-                guard.insert(key.to_owned(), ValueWithAccess(None, acl));
+                if guard.len() >= get_limits().max_private_keys {
+                    return Err(BusError::Limit("too many keys".to_owned()));
+                }
+                guard.insert(
+                    key.to_owned(),
+                    ValueWithAccess {
+                        value: None,
+                        access: acl,
+                        allow,
+                        deadline: None,
+                        version: 0,
+                    },
+                );
             }
         }
+        Ok(())
     }
 
     pub async fn get_private(&self, key: &ShortText) -> Option<Vec<u8>> {
         let guard = self.kvstore.lock().await;
-        match guard.get(key) {
-            Some(ValueWithAccess(value, _)) => value.to_owned(),
-            None => None,
-        }
+        guard.get(key).and_then(|va| va.current().cloned())
     }
 
-    pub async fn set_private(&self, key: &ShortText, value: Vec<u8>) {
+    pub async fn set_private(
+        &self,
+        key: &ShortText,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BusResult<()> {
+        let deadline = deadline_after(ttl)?;
         let mut guard = self.kvstore.lock().await;
-        if let Some(name) = self.name.lock().await.to_owned() {
-            get_event_broker()
-                .send(EventKey(name, key.to_owned()), Some(&value[..]))
-                .await;
+        if !guard.contains_key(key) && guard.len() >= get_limits().max_private_keys {
+            return Err(BusError::Limit("too many keys".to_owned()));
         }
-        guard.insert(
-            key.to_owned(),
-            ValueWithAccess(Some(value), AccessTag::Public),
-        );
+        self.emit(key, Some(&value[..])).await;
+        let version = self.revision.fetch_add(1, Ordering::Relaxed) + 1;
+        let va = guard.entry(key.to_owned()).or_insert(ValueWithAccess {
+            value: None,
+            access: AccessTag::Public,
+            allow: None,
+            deadline: None,
+            version,
+        });
+        va.value.replace(value);
+        va.deadline = deadline;
+        va.version = version;
+        Ok(())
     }
 
     pub async fn del_private(&self, key: &ShortText) {
         let mut guard = self.kvstore.lock().await;
-        if let Some(name) = self.name.lock().await.to_owned() {
-            get_event_broker()
-                .send(EventKey(name, key.to_owned()), None)
-                .await;
-        }
+        self.emit(key, None).await;
         guard.remove(key);
     }
 
+    // the caller may be slow to accept, so never hold call_record while forwarding
     pub async fn recv_call_resp(&self, reqid: u32, payload: ResponsePayload) {
-        let mut call_record = self.call_record.lock().await;
-        if let Some(tgt) = call_record.remove(&reqid) {
-            if let Some(tgt) = tgt.upgrade() {
-                tgt.call_resp(reqid, payload).await;
+        let record = self.call_record.lock().await.remove(&reqid);
+        if let Some(tgt) = record.and_then(|record| record.caller.upgrade()) {
+            tgt.call_resp(reqid, payload).await;
+        }
+    }
+
+    pub async fn recv_call_next(&self, reqid: u32, data: Vec<u8>) {
+        let caller = {
+            let mut call_record = self.call_record.lock().await;
+            match call_record.get_mut(&reqid) {
+                Some(record) => {
+                    if let Some(deadline) = Instant::now().checked_add(record.timeout) {
+                        record.deadline = deadline;
+                    }
+                    record.caller.upgrade()
+                }
+                None => None,
+            }
+        };
+        if let Some(tgt) = caller {
+            tgt.call_next(reqid, data).await;
+        }
+    }
+
+    pub async fn fail_calls(&self, reason: BusError) {
+        let records = std::mem::take(&mut *self.call_record.lock().await);
+        for (id, record) in records {
+            if let Some(caller) = record.caller.upgrade() {
+                caller
+                    .call_resp(id, ResponsePayload::Failed(reason.clone()))
+                    .await;
             }
         }
     }
 
-    pub async fn register_event(&self, reqid: u32, ek: EventKey) {
+    pub async fn bind_call(&self, reqid: u32, callee: &Arc<dyn Entity>) {
+        let mut guard = self.pending_call.lock().await;
+        if let Some(call) = guard.values_mut().find(|call| call.reqid == reqid) {
+            call.callee = Some(Arc::downgrade(callee));
+        }
+    }
+
+    pub async fn cancel_call(&self, reqid: u32) -> bool {
+        let mut guard = self.pending_call.lock().await;
+        let resid = match guard.iter().find(|(_, call)| call.reqid == reqid) {
+            Some((resid, _)) => *resid,
+            None => return false,
+        };
+        let call = guard.remove(&resid).unwrap();
+        drop(guard);
+        if let Some(callee) = call.callee.and_then(|callee| callee.upgrade()) {
+            callee.cancel(resid).await;
+        }
+        true
+    }
+
+    pub async fn register_event(&self, reqid: u32, ek: EventKey) -> BusResult<()> {
         let mut guard = self.event_subscribe.lock().await;
+        if !guard.contains_key(&ek) && guard.len() >= get_limits().max_subscriptions {
+            return Err(BusError::Limit("too many subscriptions".to_owned()));
+        }
         guard.insert(ek, reqid);
+        Ok(())
     }
 
-    pub async fn register_notify(&self, reqid: u32, ek: EventKey) {
+    pub async fn register_notify(&self, reqid: u32, ek: EventKey) -> BusResult<()> {
         let mut guard = self.notify_subscribe.lock().await;
+        if !guard.contains_key(&ek) && guard.len() >= get_limits().max_subscriptions {
+            return Err(BusError::Limit("too many subscriptions".to_owned()));
+        }
         guard.insert(ek, reqid);
+        Ok(())
     }
 
-    pub fn new(writer: Writer) -> ExternalEntity<Writer> {
+    pub async fn hold_event(&self, reqid: u32) {
+        let mut guard = self.backlog.lock().await;
+        guard.insert(reqid, Vec::new());
+    }
+
+    pub async fn release_event(&self, reqid: u32, snapshot: Snapshot) -> Result<()> {
+        let mut guard = self.backlog.lock().await;
+        let pending = guard.remove(&reqid).unwrap_or_default();
+        for (key, data) in snapshot.into_iter().chain(pending) {
+            let data = data.as_deref();
+            let payload = self.build_next(EventKind::of(data), &key, data).await;
+            self.send(Response::new_next(reqid, payload)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn unregister_event(
+        &self,
+        reqid: u32,
+        ek: Option<EventKey>,
+    ) -> Option<(EventKey, u32)> {
+        let mut guard = self.event_subscribe.lock().await;
+        take_subscription(&mut guard, reqid, ek)
+    }
+
+    pub async fn unregister_notify(
+        &self,
+        reqid: u32,
+        ek: Option<EventKey>,
+    ) -> Option<(EventKey, u32)> {
+        let mut guard = self.notify_subscribe.lock().await;
+        take_subscription(&mut guard, reqid, ek)
+    }
+
+    pub fn new(
+        writer: Writer,
+        protocol: Handshake,
+        identity: Option<String>,
+    ) -> ExternalEntity<Writer> {
         ExternalEntity {
-            name: Mutex::new(None),
+            protocol,
+            identity,
+            names: Mutex::new(Vec::new()),
             writer: Mutex::new(writer),
             kvstore: Mutex::new(HashMap::with_capacity(32)),
+            revision: AtomicU64::new(0),
             pending_call: Mutex::new(BTreeMap::new()),
             call_record: Mutex::new(BTreeMap::new()),
             event_subscribe: Mutex::new(HashMap::with_capacity(8)),
+            backlog: Mutex::new(HashMap::new()),
             notify_subscribe: Mutex::new(HashMap::with_capacity(8)),
         }
     }
 }
+
+#[cfg(test)]
+impl ExternalEntity<Vec<u8>> {
+    // a peer that writes into memory, for tests
+    pub fn in_memory(caps: Capabilities, identity: Option<&str>) -> ExternalEntity<Vec<u8>> {
+        let protocol = Handshake {
+            version: crate::handshake::PROTOCOL_VERSION,
+            caps,
+        };
+        ExternalEntity::new(Vec::new(), protocol, identity.map(str::to_owned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(entity: &str, key: &str) -> EventKey {
+        EventKey(entity.parse().unwrap(), key.parse().unwrap())
+    }
+
+    fn value(access: AccessTag, allow: Option<&[&str]>) -> ValueWithAccess {
+        ValueWithAccess {
+            value: None,
+            access,
+            allow: allow.map(|x| x.iter().map(|x| (*x).to_owned()).collect()),
+            deadline: None,
+            version: 0,
+        }
+    }
+
+    #[test]
+    fn permits_owner() {
+        for access in &[AccessTag::Private, AccessTag::Protected, AccessTag::Public] {
+            for allow in &[None, Some(&["alice"][..])] {
+                let va = value(*access, *allow);
+                assert!(va.permits(true, None, false));
+                assert!(va.permits(true, None, true));
+                assert!(va.permits(true, Some("bob"), true));
+            }
+        }
+    }
+
+    #[test]
+    fn permits_by_access_tag() {
+        for identity in &[None, Some("alice")] {
+            let va = value(AccessTag::Private, None);
+            assert!(!va.permits(false, *identity, false));
+            assert!(!va.permits(false, *identity, true));
+            let va = value(AccessTag::Protected, None);
+            assert!(va.permits(false, *identity, false));
+            assert!(!va.permits(false, *identity, true));
+            let va = value(AccessTag::Public, None);
+            assert!(va.permits(false, *identity, false));
+            assert!(va.permits(false, *identity, true));
+        }
+    }
+
+    #[test]
+    fn permits_allowlist() {
+        let allow = Some(&["alice", "bob"][..]);
+        let va = value(AccessTag::Public, allow);
+        assert!(va.permits(false, Some("alice"), true));
+        assert!(va.permits(false, Some("bob"), false));
+        assert!(!va.permits(false, Some("carol"), false));
+        assert!(!va.permits(false, None, false));
+        let va = value(AccessTag::Protected, allow);
+        assert!(va.permits(false, Some("alice"), false));
+        assert!(!va.permits(false, Some("alice"), true));
+        assert!(!va.permits(false, None, false));
+        let va = value(AccessTag::Private, allow);
+        assert!(!va.permits(false, Some("alice"), false));
+    }
+
+    #[async_std::test]
+    async fn protected_key_is_read_only_for_others() {
+        let owner = Arc::new(ExternalEntity::in_memory(Capabilities::NONE, None));
+        let other = ExternalEntity::in_memory(Capabilities::NONE, None);
+        let other = Arc::new(other) as Arc<dyn Entity>;
+        let alice = ExternalEntity::in_memory(Capabilities::NONE, Some("alice"));
+        let alice = Arc::new(alice) as Arc<dyn Entity>;
+        let key = ShortText::build(b"k");
+        owner
+            .set_acl(&key, AccessTag::Protected, Some(vec!["alice".to_owned()]))
+            .await
+            .unwrap();
+        let sender = Arc::clone(&owner) as Arc<dyn Entity>;
+        let ret = owner.set(Some(&sender), &key, b"1".to_vec(), None).await;
+        assert_eq!(ret, Ok(()));
+        assert_eq!(owner.get(Some(&alice), &key).await, Ok(Some(b"1".to_vec())));
+        assert!(matches!(
+            owner.get(Some(&other), &key).await,
+            Err(BusError::Forbidden(_))
+        ));
+        assert!(matches!(
+            owner.get(None, &key).await,
+            Err(BusError::Forbidden(_))
+        ));
+        assert!(matches!(
+            owner.set(Some(&alice), &key, b"2".to_vec(), None).await,
+            Err(BusError::Forbidden(_))
+        ));
+        assert!(matches!(
+            owner.del(Some(&alice), &key).await,
+            Err(BusError::Forbidden(_))
+        ));
+        owner.del(Some(&sender), &key).await.unwrap();
+    }
+
+    #[test]
+    fn add_integer_to_missing_value() {
+        assert_eq!(add_integer(None, 1), Ok(1));
+        assert_eq!(add_integer(None, -5), Ok(-5));
+    }
+
+    #[test]
+    fn add_integer_to_stored_text() {
+        assert_eq!(add_integer(Some(&b"41".to_vec()), 1), Ok(42));
+        assert_eq!(add_integer(Some(&b" -3\n".to_vec()), 2), Ok(-1));
+    }
+
+    #[test]
+    fn add_integer_rejects_bad_values() {
+        assert!(matches!(
+            add_integer(Some(&b"abc".to_vec()), 1),
+            Err(BusError::Malformed(_))
+        ));
+        assert!(matches!(
+            add_integer(Some(&b"".to_vec()), 1),
+            Err(BusError::Malformed(_))
+        ));
+        let max = i64::MAX.to_string().into_bytes();
+        assert!(matches!(
+            add_integer(Some(&max), 1),
+            Err(BusError::Malformed(_))
+        ));
+        assert_eq!(add_integer(Some(&max), -1), Ok(i64::MAX - 1));
+    }
+
+    #[async_std::test]
+    async fn call_timeout_out_of_range() {
+        let callee = ExternalEntity::in_memory(Capabilities::NONE, None);
+        let caller = ExternalEntity::in_memory(Capabilities::NONE, None);
+        let caller = Arc::new(caller) as Arc<dyn EntityReceiver>;
+        let method = ShortText::build(b"m");
+        let timeout = Duration::new(u64::MAX, 0);
+        let ret = callee.call(&caller, 1, &method, b"", timeout).await;
+        assert!(matches!(ret, Err(BusError::Malformed(_))));
+        assert_eq!(callee.pending_calls().await, 0);
+        let timeout = Duration::from_secs(1);
+        let ret = callee.call(&caller, 1, &method, b"", timeout).await;
+        assert!(ret.is_ok());
+        assert_eq!(callee.pending_calls().await, 1);
+    }
+
+    struct Stalled(async_std::sync::Sender<()>);
+
+    #[async_trait]
+    impl EntityReceiver for Stalled {
+        async fn call_next(&self, _reqid: u32, _data: Vec<u8>) {
+            self.0.send(()).await;
+            async_std::future::pending::<()>().await;
+        }
+        async fn call_resp(&self, _reqid: u32, _val: ResponsePayload) {}
+    }
+
+    #[async_std::test]
+    async fn stalled_caller_does_not_block_callee() {
+        let callee = Arc::new(ExternalEntity::in_memory(Capabilities::NONE, None));
+        let (s, r) = async_std::sync::channel(1);
+        let caller = Arc::new(Stalled(s)) as Arc<dyn EntityReceiver>;
+        let method = ShortText::build(b"m");
+        let timeout = Duration::from_secs(1);
+        let ret = callee.call(&caller, 1, &method, b"", timeout).await;
+        assert!(ret.is_ok());
+        let resid = *callee.call_record.lock().await.keys().next().unwrap();
+        let stalled = Arc::clone(&callee);
+        async_std::task::spawn(async move { stalled.recv_call_next(resid, Vec::new()).await });
+        r.recv().await.unwrap();
+        let pending = async_std::io::timeout(timeout, async { Ok(callee.pending_calls().await) });
+        assert_eq!(pending.await.unwrap(), 1);
+        callee.expire(Instant::now() + timeout * 2).await;
+        assert_eq!(callee.pending_calls().await, 0);
+    }
+
+    #[async_std::test]
+    async fn extended_next_carries_kind_and_key() {
+        let entity = ExternalEntity::in_memory(Capabilities::EXTENDED_NEXT, None);
+        let ek = key("shared", "a");
+        let payload = entity.build_next(EventKind::Set, &ek, Some(b"hi")).await;
+        assert!(matches!(payload,
+            ResponsePayload::SuccessWithData(x) if x == b"\x00\x06shared\x01ahi"));
+        let payload = entity.build_next(EventKind::Delete, &ek, None).await;
+        assert!(matches!(payload,
+            ResponsePayload::SuccessWithData(x) if x == b"\x01\x06shared\x01a"));
+        let payload = entity.build_next(EventKind::Notify, &ek, Some(b"")).await;
+        assert!(matches!(payload,
+            ResponsePayload::SuccessWithData(x) if x == b"\x02\x06shared\x01a"));
+    }
+
+    #[async_std::test]
+    async fn plain_next_without_capability() {
+        let entity = ExternalEntity::in_memory(Capabilities::NONE, None);
+        let ek = key("shared", "a");
+        let payload = entity.build_next(EventKind::Set, &ek, Some(b"hi")).await;
+        assert!(matches!(payload, ResponsePayload::SuccessWithData(x) if x == b"hi"));
+        let payload = entity.build_next(EventKind::Delete, &ek, None).await;
+        assert!(matches!(payload, ResponsePayload::Success));
+    }
+
+    #[async_std::test]
+    async fn unsubscribe_by_key_or_reqid() {
+        let entity = ExternalEntity::in_memory(Capabilities::NONE, None);
+        entity.register_event(1, key("a", "x")).await.unwrap();
+        entity.register_event(2, key("a", "y")).await.unwrap();
+        entity.register_notify(3, key("b", "*")).await.unwrap();
+        let ret = entity.unregister_event(9, Some(key("a", "y"))).await;
+        assert_eq!(ret, Some((key("a", "y"), 2)));
+        assert_eq!(entity.unregister_event(2, Some(key("a", "y"))).await, None);
+        assert_eq!(entity.unregister_event(5, None).await, None);
+        let ret = entity.unregister_event(1, None).await;
+        assert_eq!(ret, Some((key("a", "x"), 1)));
+        assert_eq!(entity.unregister_event(1, None).await, None);
+        assert_eq!(entity.unregister_event(3, None).await, None);
+        let ret = entity.unregister_notify(3, None).await;
+        assert_eq!(ret, Some((key("b", "*"), 3)));
+    }
+
+    #[async_std::test]
+    async fn disconnect_fails_outstanding_calls() {
+        let callee = ExternalEntity::in_memory(Capabilities::NONE, None);
+        let caller = Arc::new(ExternalEntity::in_memory(Capabilities::NONE, None));
+        let gone = ExternalEntity::in_memory(Capabilities::NONE, None);
+        let gone = Arc::new(gone) as Arc<dyn EntityReceiver>;
+        let live = Arc::clone(&caller) as Arc<dyn EntityReceiver>;
+        let method = ShortText::build(b"m");
+        let timeout = Duration::from_secs(60);
+        callee.call(&live, 7, &method, b"", timeout).await.unwrap();
+        callee.call(&gone, 8, &method, b"", timeout).await.unwrap();
+        drop(gone);
+        assert_eq!(callee.pending_calls().await, 2);
+        callee
+            .fail_calls(BusError::Remote("callee disconnected".to_owned()))
+            .await;
+        assert_eq!(callee.pending_calls().await, 0);
+        assert!(caller.pending_call.lock().await.is_empty());
+        let written = caller.writer.lock().await.clone();
+        let res: Response = written.as_slice().decode().await.unwrap();
+        assert_eq!(res.reqid, 7);
+        assert!(matches!(res.payload,
+            ResponsePayload::Failed(BusError::Remote(x)) if x == "callee disconnected"));
+    }
+}
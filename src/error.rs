@@ -0,0 +1,164 @@
+use crate::utils::{DecoderUtils, EncoderUtils};
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusError {
+    NotFound(String),
+    Forbidden(String),
+    Duplicate(String),
+    NotSupported(String),
+    Timeout(String),
+    Limit(String),
+    Malformed(String),
+    Remote(String),
+}
+
+pub type BusResult<T> = std::result::Result<T, BusError>;
+
+impl BusError {
+    pub fn code(&self) -> usize {
+        match self {
+            BusError::NotFound(_) => 1,
+            BusError::Forbidden(_) => 2,
+            BusError::Duplicate(_) => 3,
+            BusError::NotSupported(_) => 4,
+            BusError::Timeout(_) => 5,
+            BusError::Limit(_) => 6,
+            BusError::Malformed(_) => 7,
+            BusError::Remote(_) => 8,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            BusError::NotFound(x)
+            | BusError::Forbidden(x)
+            | BusError::Duplicate(x)
+            | BusError::NotSupported(x)
+            | BusError::Timeout(x)
+            | BusError::Limit(x)
+            | BusError::Malformed(x)
+            | BusError::Remote(x) => x,
+        }
+    }
+
+    pub fn from_code(code: usize, message: String) -> BusError {
+        match code {
+            1 => BusError::NotFound(message),
+            2 => BusError::Forbidden(message),
+            3 => BusError::Duplicate(message),
+            4 => BusError::NotSupported(message),
+            5 => BusError::Timeout(message),
+            6 => BusError::Limit(message),
+            7 => BusError::Malformed(message),
+            _ => BusError::Remote(message),
+        }
+    }
+
+    // varuint code followed by the message
+    pub async fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.encode_varuint(self.code()).await.unwrap();
+        buf.extend_from_slice(self.message().as_bytes());
+        buf
+    }
+
+    pub async fn decode(mut payload: &[u8]) -> BusError {
+        match payload.decode_varuint().await {
+            Ok(code) => BusError::from_code(code, String::from_utf8_lossy(payload).into_owned()),
+            Err(_) => BusError::Malformed("invalid error payload".to_owned()),
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            BusError::NotFound(_) => io::ErrorKind::NotFound,
+            BusError::Forbidden(_) => io::ErrorKind::PermissionDenied,
+            BusError::Duplicate(_) => io::ErrorKind::AlreadyExists,
+            BusError::NotSupported(_) => io::ErrorKind::Unsupported,
+            BusError::Timeout(_) => io::ErrorKind::TimedOut,
+            BusError::Malformed(_) => io::ErrorKind::InvalidData,
+            BusError::Limit(_) | BusError::Remote(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<io::Error> for BusError {
+    fn from(e: io::Error) -> BusError {
+        if e.get_ref().map(|x| x.is::<BusError>()).unwrap_or(false) {
+            return *e.into_inner().unwrap().downcast::<BusError>().unwrap();
+        }
+        let message = e.to_string();
+        match e.kind() {
+            io::ErrorKind::NotFound => BusError::NotFound(message),
+            io::ErrorKind::PermissionDenied => BusError::Forbidden(message),
+            io::ErrorKind::AlreadyExists => BusError::Duplicate(message),
+            io::ErrorKind::Unsupported => BusError::NotSupported(message),
+            io::ErrorKind::TimedOut => BusError::Timeout(message),
+            io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::UnexpectedEof => BusError::Malformed(message),
+            _ => BusError::Remote(message),
+        }
+    }
+}
+
+impl From<BusError> for io::Error {
+    fn from(e: BusError) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn encode_decode() {
+        for code in 1..=8 {
+            let e = BusError::from_code(code, "message".to_owned());
+            assert_eq!(e.code(), code);
+            assert_eq!(BusError::decode(&e.encode().await).await, e);
+        }
+        let e = BusError::decode(&[]).await;
+        assert_eq!(e.code(), BusError::Malformed(String::new()).code());
+    }
+
+    #[test]
+    fn unknown_code_is_remote() {
+        assert_eq!(
+            BusError::from_code(0, "x".to_owned()),
+            BusError::Remote("x".to_owned())
+        );
+        assert_eq!(
+            BusError::from_code(99, "x".to_owned()),
+            BusError::Remote("x".to_owned())
+        );
+    }
+
+    #[test]
+    fn io_conversions() {
+        let e = io::Error::from(BusError::Limit("too many".to_owned()));
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(BusError::from(e), BusError::Limit("too many".to_owned()));
+
+        let e = io::Error::from(BusError::Timeout("late".to_owned()));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        let e = BusError::from(io::Error::new(io::ErrorKind::PermissionDenied, "no"));
+        assert_eq!(e, BusError::Forbidden("no".to_owned()));
+        let e = BusError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert_eq!(e.code(), BusError::Malformed(String::new()).code());
+        let e = BusError::from(io::Error::new(io::ErrorKind::BrokenPipe, "boom"));
+        assert_eq!(e, BusError::Remote("boom".to_owned()));
+    }
+}
@@ -1,20 +1,124 @@
+use crate::auth::authenticate;
 use crate::broker::*;
 use crate::entity::*;
+use crate::error::{BusError, BusResult};
+use crate::handshake::*;
+use crate::limits::get_limits;
 use crate::packet::*;
+use crate::policy::{self, Permission};
 use crate::registry::*;
+use crate::short_text::ShortText;
 use crate::utils::*;
 use anyhow::{anyhow, Result};
 use async_std::io;
 use async_std::io::{BufReader, BufWriter, Read, Write};
-use async_std::net;
-use async_std::prelude::*;
 use async_std::sync::Arc;
 use async_std::task;
 use log::debug;
+use std::convert::TryFrom;
 use std::time::Duration;
 
-fn errtoresp(e: std::io::Error) -> ResponsePayload {
-    ResponsePayload::Failed(format!("{}", e).as_bytes().to_vec())
+fn errtoresp(e: BusError) -> ResponsePayload {
+    ResponsePayload::Failed(e)
+}
+
+fn target_not_found() -> ResponsePayload {
+    errtoresp(BusError::NotFound("target not found".to_owned()))
+}
+
+async fn required_permission(
+    request: &Request,
+) -> io::Result<Option<(Permission, ShortText, ShortText)>> {
+    let mut payload = request.payload.as_slice();
+    let permission = match request.command.as_bytes() {
+        b"SET" | b"SET TTL" | b"DEL" | b"CAS" | b"CAS VALUE" | b"SETNX" | b"INCR" | b"DECR" => {
+            Permission::Set
+        }
+        b"GET" | b"GET VERSIONED" | b"KEYS" => Permission::Get,
+        b"CALL" | b"CALL TIMEOUT" => Permission::Call,
+        b"LISTEN" => Permission::Listen,
+        b"OBSERVE" | b"OBSERVE RETAIN" => Permission::Observe,
+        b"JOIN" => {
+            let name = payload.decode_short_text().await?;
+            return Ok(Some((Permission::Name, ShortText::build(b"registry"), name)));
+        }
+        _ => return Ok(None),
+    };
+    let target = payload.decode_short_text().await?;
+    let key = if request.command.as_bytes() == b"KEYS" {
+        ShortText::build(b"*")
+    } else {
+        payload.decode_short_text().await?
+    };
+    let permission = if permission == Permission::Set {
+        policy::write_permission(&target)
+    } else {
+        permission
+    };
+    Ok(Some((permission, target, key)))
+}
+
+async fn encode_version(version: u64, data: Option<Vec<u8>>) -> ResponsePayload {
+    let mut buf = Vec::new();
+    buf.encode_varuint(version as usize).await.unwrap();
+    if let Some(data) = data {
+        buf.extend_from_slice(&data);
+    }
+    ResponsePayload::SuccessWithData(buf)
+}
+
+async fn casresp(result: BusResult<CasResult>, protocol: &Handshake) -> ResponsePayload {
+    match result {
+        Ok(CasResult::Applied(version)) => encode_version(version, None).await,
+        Ok(CasResult::Mismatch(version)) if protocol.supports(Capabilities::VERSION_MISMATCH) => {
+            ResponsePayload::Mismatch(version)
+        }
+        Ok(CasResult::Mismatch(version)) => errtoresp(BusError::Duplicate(format!(
+            "version mismatch: {}",
+            version
+        ))),
+        Err(e) => errtoresp(e),
+    }
+}
+
+async fn decode_subscription(mut payload: &[u8]) -> io::Result<Option<EventKey>> {
+    if payload.is_empty() {
+        Ok(None)
+    } else {
+        let target = payload.decode_short_text().await?;
+        let key = payload.decode_short_text().await?;
+        Ok(Some(EventKey(target, key)))
+    }
+}
+
+async fn finish_subscription<Writer: 'static + Write + Unpin + Send>(
+    entity: &Arc<ExternalEntity<Writer>>,
+    reqid: u32,
+    removed: Option<u32>,
+) -> io::Result<()> {
+    match removed {
+        Some(id) => {
+            entity
+                .send(Response::new_resp(id, ResponsePayload::Success))
+                .await?;
+            if id != reqid {
+                entity
+                    .send(Response::new_resp(reqid, ResponsePayload::Success))
+                    .await?;
+            }
+        }
+        None => {
+            entity
+                .send(Response::new_resp(
+                    reqid,
+                    ResponsePayload::Failed(BusError::NotFound(
+                        "subscription not found".to_owned(),
+                    )),
+                ))
+                .await?;
+        }
+    }
+    Ok(())
 }
 
 async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpin + Send>(
@@ -22,8 +126,25 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
     entity: &Arc<ExternalEntity<Writer>>,
 ) -> Result<()> {
     loop {
-        let request: Request = reader.decode().await?;
+        let request: Request = match reader.decode().await {
+            Ok(request) => request,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                entity
+                    .send(Response::new_resp(0, errtoresp(e.into())))
+                    .await?;
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
         debug!("request: {:?}", &request);
+        if let Some((permission, target, key)) = required_permission(&request).await? {
+            if let Err(e) = policy::check(entity.identity(), permission, &target, &key) {
+                entity
+                    .send(Response::new_resp(request.reqid, errtoresp(e)))
+                    .await?;
+                continue;
+            }
+        }
         match request.command.as_bytes() {
             b"STOP" => break,
             b"PING" => {
@@ -37,9 +158,24 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
             b"SET PRIVATE" => {
                 let mut payload = request.payload.as_slice();
                 let key = payload.decode_short_text().await?;
-                entity.set_private(&key, payload.to_vec()).await;
+                let value = entity
+                    .set_private(&key, payload.to_vec(), None)
+                    .await
+                    .map_or_else(errtoresp, |_| ResponsePayload::Success);
                 entity
-                    .send(Response::new_resp(request.reqid, ResponsePayload::Success))
+                    .send(Response::new_resp(request.reqid, value))
+                    .await?;
+            }
+            b"SET PRIVATE TTL" => {
+                let mut payload = request.payload.as_slice();
+                let key = payload.decode_short_text().await?;
+                let ttl = Duration::from_millis(payload.decode_varuint().await? as u64);
+                let value = entity
+                    .set_private(&key, payload.to_vec(), Some(ttl))
+                    .await
+                    .map_or_else(errtoresp, |_| ResponsePayload::Success);
+                entity
+                    .send(Response::new_resp(request.reqid, value))
                     .await?;
             }
             b"GET PRIVATE" => {
@@ -52,7 +188,7 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                     )),
                     None => entity.send(Response::new_resp(
                         request.reqid,
-                        ResponsePayload::Failed(b"not found".to_vec()),
+                        errtoresp(BusError::NotFound("not found".to_owned())),
                     )),
                 }
                 .await?;
@@ -69,20 +205,37 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                 let mut payload = request.payload.as_slice();
                 let key = payload.decode_short_text().await?;
                 let acl = payload.decode().await?;
-                entity.set_acl(&key, acl).await;
+                let allow = if payload.is_empty() {
+                    None
+                } else {
+                    let mut allow = Vec::new();
+                    for _ in 0..payload.decode_varuint().await? {
+                        allow.push(payload.decode_short_text().await?.to_string());
+                    }
+                    Some(allow)
+                };
+                let value = entity
+                    .set_acl(&key, acl, allow)
+                    .await
+                    .map_or_else(errtoresp, |_| ResponsePayload::Success);
                 entity
-                    .send(Response::new_resp(request.reqid, ResponsePayload::Success))
+                    .send(Response::new_resp(request.reqid, value))
                     .await?;
             }
-            b"SET" => {
+            b"SET" | b"SET TTL" => {
                 let mut payload = request.payload.as_slice();
                 let target = payload.decode_short_text().await?;
                 let key = payload.decode_short_text().await?;
+                let ttl = if request.command.as_bytes() == b"SET TTL" {
+                    Some(Duration::from_millis(payload.decode_varuint().await? as u64))
+                } else {
+                    None
+                };
                 let value = payload.to_vec();
                 if let Some(target) = Registry::get_global().find(&target).await {
                     let temp = Arc::clone(entity) as Arc<dyn Entity>;
                     let payload = target
-                        .set(Some(&temp), &key, value)
+                        .set(Some(&temp), &key, value, ttl)
                         .await
                         .map_or_else(errtoresp, |_| ResponsePayload::Success);
                     entity
@@ -90,10 +243,7 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                         .await?;
                 } else {
                     entity
-                        .send(Response::new_resp(
-                            request.reqid,
-                            ResponsePayload::Failed(b"target not found".to_vec()),
-                        ))
+                        .send(Response::new_resp(request.reqid, target_not_found()))
                         .await?;
                 }
             }
@@ -116,10 +266,7 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                         .await?;
                 } else {
                     entity
-                        .send(Response::new_resp(
-                            request.reqid,
-                            ResponsePayload::Failed(b"target not found".to_vec()),
-                        ))
+                        .send(Response::new_resp(request.reqid, target_not_found()))
                         .await?;
                 }
             }
@@ -138,10 +285,92 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                         .await?;
                 } else {
                     entity
-                        .send(Response::new_resp(
-                            request.reqid,
-                            ResponsePayload::Failed(b"target not found".to_vec()),
-                        ))
+                        .send(Response::new_resp(request.reqid, target_not_found()))
+                        .await?;
+                }
+            }
+            b"GET VERSIONED" => {
+                let mut payload = request.payload.as_slice();
+                let target = payload.decode_short_text().await?;
+                let key = payload.decode_short_text().await?;
+                if let Some(target) = Registry::get_global().find(&target).await {
+                    let temp = Arc::clone(entity) as Arc<dyn Entity>;
+                    let value = match target.get_versioned(Some(&temp), &key).await {
+                        Ok((version, data)) => encode_version(version, data).await,
+                        Err(e) => errtoresp(e),
+                    };
+                    entity
+                        .send(Response::new_resp(request.reqid, value))
+                        .await?;
+                } else {
+                    entity
+                        .send(Response::new_resp(request.reqid, target_not_found()))
+                        .await?;
+                }
+            }
+            b"CAS" | b"CAS VALUE" | b"SETNX" => {
+                let mut payload = request.payload.as_slice();
+                let target = payload.decode_short_text().await?;
+                let key = payload.decode_short_text().await?;
+                let cond = match request.command.as_bytes() {
+                    b"CAS" => Condition::Version(payload.decode_varuint().await? as u64),
+                    b"CAS VALUE" => Condition::Value(payload.decode_binary().await?),
+                    _ => Condition::Version(0),
+                };
+                let value = payload.to_vec();
+                if let Some(target) = Registry::get_global().find(&target).await {
+                    let temp = Arc::clone(entity) as Arc<dyn Entity>;
+                    let result = target
+                        .compare_and_set(Some(&temp), &key, cond, value, None)
+                        .await;
+                    let payload = casresp(result, entity.protocol()).await;
+                    entity
+                        .send(Response::new_resp(request.reqid, payload))
+                        .await?;
+                } else {
+                    entity
+                        .send(Response::new_resp(request.reqid, target_not_found()))
+                        .await?;
+                }
+            }
+            b"INCR" | b"DECR" => {
+                let mut payload = request.payload.as_slice();
+                let target = payload.decode_short_text().await?;
+                let key = payload.decode_short_text().await?;
+                let amount = if payload.is_empty() {
+                    Some(1)
+                } else {
+                    i64::try_from(payload.decode_varuint().await?).ok()
+                };
+                let delta = if request.command.as_bytes() == b"DECR" {
+                    amount.and_then(i64::checked_neg)
+                } else {
+                    amount
+                };
+                let delta = match delta {
+                    Some(delta) => delta,
+                    None => {
+                        let e = BusError::Malformed("integer overflow".to_owned());
+                        entity
+                            .send(Response::new_resp(request.reqid, errtoresp(e)))
+                            .await?;
+                        continue;
+                    }
+                };
+                if let Some(target) = Registry::get_global().find(&target).await {
+                    let temp = Arc::clone(entity) as Arc<dyn Entity>;
+                    let value = target
+                        .increment(Some(&temp), &key, delta)
+                        .await
+                        .map_or_else(errtoresp, |value| {
+                            ResponsePayload::SuccessWithData(value.to_string().into_bytes())
+                        });
+                    entity
+                        .send(Response::new_resp(request.reqid, value))
+                        .await?;
+                } else {
+                    entity
+                        .send(Response::new_resp(request.reqid, target_not_found()))
                         .await?;
                 }
             }
@@ -172,10 +401,7 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                     }
                 } else {
                     entity
-                        .send(Response::new_resp(
-                            request.reqid,
-                            ResponsePayload::Failed(b"target not found".to_vec()),
-                        ))
+                        .send(Response::new_resp(request.reqid, target_not_found()))
                         .await?;
                 }
             }
@@ -183,10 +409,21 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                 let mut payload = request.payload.as_slice();
                 let key = payload.decode_short_text().await?;
                 let value = payload.to_vec();
-                if let Some(name) = entity.get_name().await {
-                    get_notify_broker()
-                        .send(EventKey(name, key), Some(&value))
-                        .await;
+                let names = entity.get_names().await;
+                let denied = names
+                    .iter()
+                    .map(|name| policy::check(entity.identity(), Permission::Notify, name, &key))
+                    .find_map(|x| x.err());
+                if let Some(e) = denied {
+                    entity
+                        .send(Response::new_resp(request.reqid, errtoresp(e)))
+                        .await?;
+                } else if !names.is_empty() {
+                    for name in names {
+                        get_notify_broker()
+                            .send(EventKey(name, key), Some(&value))
+                            .await;
+                    }
                     entity
                         .send(Response::new_resp(request.reqid, ResponsePayload::Success))
                         .await?;
@@ -194,7 +431,7 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                     entity
                         .send(Response::new_resp(
                             request.reqid,
-                            ResponsePayload::Failed(b"no name".to_vec()),
+                            ResponsePayload::Failed(BusError::NotFound("no name".to_owned())),
                         ))
                         .await?;
                 }
@@ -203,12 +440,18 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                 let mut payload = request.payload.as_slice();
                 let target = payload.decode_short_text().await?;
                 let key = payload.decode_short_text().await?;
+                if let Err(e) = entity
+                    .register_notify(request.reqid, EventKey(target, key))
+                    .await
+                {
+                    entity
+                        .send(Response::new_resp(request.reqid, errtoresp(e)))
+                        .await?;
+                    continue;
+                }
                 let broker = get_notify_broker();
                 let temp = Arc::clone(&entity);
                 broker.register(temp, EventKey(target, key)).await;
-                entity
-                    .register_notify(request.reqid, EventKey(target, key))
-                    .await;
                 entity
                     .send(Response::new_resp(request.reqid, ResponsePayload::Success))
                     .await?;
@@ -217,37 +460,139 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                 let mut payload = request.payload.as_slice();
                 let target = payload.decode_short_text().await?;
                 let key = payload.decode_short_text().await?;
+                if let Err(e) = entity
+                    .register_event(request.reqid, EventKey(target, key))
+                    .await
+                {
+                    entity
+                        .send(Response::new_resp(request.reqid, errtoresp(e)))
+                        .await?;
+                    continue;
+                }
                 let broker = get_event_broker();
                 let temp = Arc::clone(&entity);
                 broker.register(temp, EventKey(target, key)).await;
                 entity
+                    .send(Response::new_resp(request.reqid, ResponsePayload::Success))
+                    .await?;
+            }
+            b"UNLISTEN" => {
+                let ek = decode_subscription(&request.payload).await?;
+                let removed = entity.unregister_notify(request.reqid, ek).await;
+                if let Some((key, _)) = &removed {
+                    let temp = Arc::clone(entity) as Arc<dyn NotifyReceiver>;
+                    get_notify_broker().unregister(&temp, key).await;
+                }
+                finish_subscription(entity, request.reqid, removed.map(|(_, id)| id)).await?;
+            }
+            b"UNOBSERVE" => {
+                let ek = decode_subscription(&request.payload).await?;
+                let removed = entity.unregister_event(request.reqid, ek).await;
+                if let Some((key, _)) = &removed {
+                    let temp = Arc::clone(entity) as Arc<dyn EventReceiver>;
+                    get_event_broker().unregister(&temp, key).await;
+                }
+                finish_subscription(entity, request.reqid, removed.map(|(_, id)| id)).await?;
+            }
+            b"OBSERVE RETAIN" => {
+                let mut payload = request.payload.as_slice();
+                let target = payload.decode_short_text().await?;
+                let key = payload.decode_short_text().await?;
+                if let Err(e) = entity
                     .register_event(request.reqid, EventKey(target, key))
-                    .await;
+                    .await
+                {
+                    entity
+                        .send(Response::new_resp(request.reqid, errtoresp(e)))
+                        .await?;
+                    continue;
+                }
+                let broker = get_event_broker();
+                entity.hold_event(request.reqid).await;
+                let temp = Arc::clone(entity);
+                broker.register(temp, EventKey(target, key)).await;
                 entity
                     .send(Response::new_resp(request.reqid, ResponsePayload::Success))
                     .await?;
+                let temp = Arc::clone(entity) as Arc<dyn Entity>;
+                let snapshot = Registry::get_global()
+                    .snapshot(Some(&temp), &EventKey(target, key))
+                    .await;
+                entity.release_event(request.reqid, snapshot).await?;
+            }
+            b"JOIN" | b"LEAVE" => {
+                let mut payload = request.payload.as_slice();
+                let name = payload.decode_short_text().await?;
+                let temp = Arc::clone(entity) as Arc<dyn Entity>;
+                let res = if request.command.as_bytes() == b"JOIN" {
+                    Registry::get_global().join(&temp, &name).await
+                } else {
+                    Registry::get_global().leave(&temp, &name).await
+                };
+                let payload = match res {
+                    Ok(()) => ResponsePayload::Success,
+                    Err(e) => errtoresp(e),
+                };
+                entity
+                    .send(Response::new_resp(request.reqid, payload))
+                    .await?;
             }
-            b"CALL" => {
+            b"CALL" | b"CALL TIMEOUT" => {
                 let mut payload = request.payload.as_slice();
                 let target = payload.decode_short_text().await?;
                 let key = payload.decode_short_text().await?;
+                let timeout = if request.command.as_bytes() == b"CALL TIMEOUT" {
+                    Duration::from_millis(payload.decode_varuint().await? as u64)
+                } else {
+                    get_limits().call_timeout
+                };
                 let value = payload;
-                if let Some(target) = Registry::get_global().find(&target).await {
-                    let temp = Arc::clone(&entity) as Arc<dyn EntityReceiver>;
-                    if let Err(e) = target.call(&temp, request.reqid, &key, value).await {
-                        entity
-                            .send(Response::new_resp(request.reqid, errtoresp(e)))
-                            .await?;
+                if let Err(e) = Registry::get_global().check_call(&target, &key, value).await {
+                    entity
+                        .send(Response::new_resp(request.reqid, errtoresp(e)))
+                        .await?;
+                } else if let Some(target) = Registry::get_global().find(&target).await {
+                    let target = match target.select_callee().await {
+                        Ok(Some(member)) => member,
+                        Ok(None) => target,
+                        Err(e) => {
+                            entity
+                                .send(Response::new_resp(request.reqid, errtoresp(e)))
+                                .await?;
+                            continue;
+                        }
+                    };
+                    let temp = Arc::clone(entity) as Arc<dyn EntityReceiver>;
+                    match target
+                        .call(&temp, request.reqid, &key, value, timeout)
+                        .await
+                    {
+                        Ok(()) => {
+                            Registry::get_global().track_callee(&target).await;
+                            entity.bind_call(request.reqid, &target).await
+                        }
+                        Err(e) => {
+                            entity
+                                .send(Response::new_resp(request.reqid, errtoresp(e)))
+                                .await?
+                        }
                     }
                 } else {
                     entity
-                        .send(Response::new_resp(
-                            request.reqid,
-                            ResponsePayload::Failed(b"target not found".to_vec()),
-                        ))
+                        .send(Response::new_resp(request.reqid, target_not_found()))
                         .await?;
                 }
             }
+            b"CANCEL" => {
+                let payload = if entity.cancel_call(request.reqid).await {
+                    ResponsePayload::Failed(BusError::Remote("cancelled".to_owned()))
+                } else {
+                    ResponsePayload::Failed(BusError::NotFound("call not found".to_owned()))
+                };
+                entity
+                    .send(Response::new_resp(request.reqid, payload))
+                    .await?;
+            }
             b"RESPONSE" => {
                 entity
                     .recv_call_resp(
@@ -256,16 +601,26 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
                     )
                     .await;
             }
+            b"RESPONSE NEXT" => {
+                entity.recv_call_next(request.reqid, request.payload).await;
+            }
             b"EXCEPTION" => {
+                let e = if entity.protocol().supports(Capabilities::ERROR_CODES) {
+                    BusError::decode(&request.payload).await
+                } else {
+                    BusError::Remote(String::from_utf8_lossy(&request.payload).into_owned())
+                };
                 entity
-                    .recv_call_resp(request.reqid, ResponsePayload::Failed(request.payload))
+                    .recv_call_resp(request.reqid, ResponsePayload::Failed(e))
                     .await;
             }
             _ => {
                 entity
                     .send(Response::new_resp(
                         request.reqid,
-                        ResponsePayload::Failed(b"Unknown command".to_vec()),
+                        ResponsePayload::Failed(BusError::NotSupported(
+                            "unknown command".to_owned(),
+                        )),
                     ))
                     .await?;
                 break;
@@ -275,18 +630,47 @@ async fn handle_loop<Reader: Read + Unpin + Send, Writer: 'static + Write + Unpi
     Ok(())
 }
 
-pub async fn handle_client(stream: net::TcpStream) -> Result<()> {
-    let mut reader = BufReader::new(stream.clone());
-    let mut writer = BufWriter::new(stream.clone());
-    if !io::timeout(Duration::from_secs(1), verify_client(&mut reader)).await? {
-        return Err(anyhow!("Fake client detected!"));
-    }
-    writer.write(b"OK").await?;
-    writer.flush().await?;
-    let entity = Arc::new(ExternalEntity::new(writer));
+pub async fn handle_client<Stream>(stream: Stream, peer: Option<String>) -> Result<()>
+where
+    Stream: 'static + Read + Write + Clone + Unpin + Send + Sync,
+{
+    handle_connection(stream.clone(), stream, peer).await
+}
+
+pub async fn handle_connection<Reader, Writer>(
+    reader: Reader,
+    writer: Writer,
+    peer: Option<String>,
+) -> Result<()>
+where
+    Reader: Read + Unpin + Send,
+    Writer: 'static + Write + Unpin + Send,
+{
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let offer = match io::timeout(Duration::from_secs(1), read_offer(&mut reader)).await? {
+        Some(offer) => offer,
+        None => return Err(anyhow!("Fake client detected!")),
+    };
+    let protocol = reply_offer(&mut writer, offer).await?;
+    let identity = io::timeout(
+        Duration::from_secs(5),
+        authenticate(&mut reader, &mut writer, &protocol),
+    )
+    .await?
+    .or(peer);
+    let entity = Arc::new(ExternalEntity::new(writer, protocol, identity));
+    debug!(
+        "negotiated: {:?}, identity: {:?}",
+        entity.protocol(),
+        entity.identity()
+    );
     let ret = handle_loop(&mut reader, &entity).await;
+    entity
+        .fail_calls(BusError::Remote("callee disconnected".to_owned()))
+        .await;
     drop(entity);
-    drop(stream);
+    drop(reader);
     task::spawn(get_event_broker().cleanup());
     task::spawn(get_notify_broker().cleanup());
     ret
@@ -0,0 +1,201 @@
+use crate::entity::*;
+use crate::error::{BusError, BusResult};
+use crate::short_text::ShortText;
+use async_std::sync::{Arc, Mutex, Weak};
+use async_trait::async_trait;
+use rand::{thread_rng, Rng};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupPolicy {
+    RoundRobin,
+    Random,
+    LeastPending,
+}
+
+impl FromStr for GroupPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "round-robin" => Ok(GroupPolicy::RoundRobin),
+            "random" => Ok(GroupPolicy::Random),
+            "least-pending" => Ok(GroupPolicy::LeastPending),
+            _ => Err(format!("unknown group policy: {}", s)),
+        }
+    }
+}
+
+pub struct ServiceGroup {
+    policy: GroupPolicy,
+    members: Mutex<Vec<Weak<dyn Entity>>>,
+    cursor: AtomicUsize,
+}
+
+impl ServiceGroup {
+    pub fn new(policy: GroupPolicy) -> ServiceGroup {
+        ServiceGroup {
+            policy,
+            members: Mutex::new(Vec::new()),
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn join(&self, member: &Arc<dyn Entity>) -> BusResult<()> {
+        let mut guard = self.members.lock().await;
+        guard.retain(|x| x.strong_count() != 0);
+        if guard.iter().any(|x| ptr_eq(x, member)) {
+            return Err(BusError::Duplicate("already joined".to_owned()));
+        }
+        guard.push(Arc::downgrade(member));
+        Ok(())
+    }
+
+    pub async fn leave(&self, member: &Arc<dyn Entity>) -> BusResult<()> {
+        let mut guard = self.members.lock().await;
+        let len = guard.len();
+        guard.retain(|x| x.strong_count() != 0 && !ptr_eq(x, member));
+        if guard.len() == len {
+            Err(BusError::NotFound("not joined".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        let mut guard = self.members.lock().await;
+        guard.retain(|x| x.strong_count() != 0);
+        guard.is_empty()
+    }
+
+    pub async fn members(&self) -> Vec<Arc<dyn Entity>> {
+        let mut guard = self.members.lock().await;
+        guard.retain(|x| x.strong_count() != 0);
+        guard.iter().filter_map(|x| x.upgrade()).collect()
+    }
+
+    async fn pick(&self) -> Option<Arc<dyn Entity>> {
+        let members = self.members().await;
+        if members.is_empty() {
+            return None;
+        }
+        let idx = match self.policy {
+            GroupPolicy::RoundRobin => self.cursor.fetch_add(1, Ordering::Relaxed) % members.len(),
+            GroupPolicy::Random => thread_rng().gen_range(0, members.len()),
+            GroupPolicy::LeastPending => {
+                let mut best = (0, usize::MAX);
+                for (idx, member) in members.iter().enumerate() {
+                    let pending = member.pending_calls().await;
+                    if pending < best.1 {
+                        best = (idx, pending);
+                    }
+                }
+                best.0
+            }
+        };
+        members.into_iter().nth(idx)
+    }
+}
+
+fn ptr_eq(weak: &Weak<dyn Entity>, target: &Arc<dyn Entity>) -> bool {
+    match weak.upgrade() {
+        Some(x) => Arc::ptr_eq(&x, target),
+        None => false,
+    }
+}
+
+#[async_trait]
+impl Entity for ServiceGroup {
+    async fn get(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        _key: &ShortText,
+    ) -> BusResult<Option<Vec<u8>>> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn set(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        _key: &ShortText,
+        _val: Vec<u8>,
+        _ttl: Option<Duration>,
+    ) -> BusResult<()> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn del(&self, _sender: Option<&Arc<dyn Entity>>, _key: &ShortText) -> BusResult<()> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn keys(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+    ) -> BusResult<Vec<(ShortText, AccessTag)>> {
+        Ok(Vec::new())
+    }
+    async fn select_callee(&self) -> BusResult<Option<Arc<dyn Entity>>> {
+        match self.pick().await {
+            Some(member) => Ok(Some(member)),
+            None => Err(BusError::NotFound("no member available".to_owned())),
+        }
+    }
+    async fn call(
+        &self,
+        sender: &Arc<dyn EntityReceiver>,
+        reqid: u32,
+        key: &ShortText,
+        val: &[u8],
+        timeout: Duration,
+    ) -> BusResult<()> {
+        match self.select_callee().await? {
+            Some(member) => member.call(sender, reqid, key, val, timeout).await,
+            None => Err(BusError::NotFound("no member available".to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::Capabilities;
+
+    fn member() -> Arc<dyn Entity> {
+        Arc::new(ExternalEntity::in_memory(Capabilities::NONE, None))
+    }
+
+    #[async_std::test]
+    async fn join_and_leave() {
+        let group = ServiceGroup::new(GroupPolicy::RoundRobin);
+        let (a, b) = (member(), member());
+        group.join(&a).await.unwrap();
+        assert!(matches!(group.join(&a).await, Err(BusError::Duplicate(_))));
+        group.join(&b).await.unwrap();
+        assert_eq!(group.members().await.len(), 2);
+        group.leave(&a).await.unwrap();
+        assert!(matches!(group.leave(&a).await, Err(BusError::NotFound(_))));
+        drop(b);
+        assert!(group.is_empty().await);
+    }
+
+    #[async_std::test]
+    async fn round_robin_selects_each_member() {
+        let group = ServiceGroup::new(GroupPolicy::RoundRobin);
+        let (a, b) = (member(), member());
+        group.join(&a).await.unwrap();
+        group.join(&b).await.unwrap();
+        let first = group.select_callee().await.unwrap().unwrap();
+        let second = group.select_callee().await.unwrap().unwrap();
+        let third = group.select_callee().await.unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &a));
+        assert!(Arc::ptr_eq(&second, &b));
+        assert!(Arc::ptr_eq(&third, &a));
+    }
+
+    #[async_std::test]
+    async fn no_member_available() {
+        let group = ServiceGroup::new(GroupPolicy::Random);
+        assert!(matches!(
+            group.select_callee().await,
+            Err(BusError::NotFound(_))
+        ));
+    }
+}
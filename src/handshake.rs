@@ -0,0 +1,165 @@
+use crate::utils::{strerr, DecoderUtils, EncoderUtils};
+use async_std::io::{Read, ReadExt, Result, Write};
+use async_std::prelude::*;
+use std::ops::BitAnd;
+
+pub const PROTOCOL_VERSION: usize = 2;
+pub const MIN_PROTOCOL_VERSION: usize = 1;
+
+const MAGIC_PREFIX: &[u8; 7] = b"MINIBUS";
+const MAGIC_LEGACY: u8 = 0;
+const MAGIC_NEGOTIATE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const EXTENDED_NEXT: Capabilities = Capabilities(1);
+    pub const ERROR_CODES: Capabilities = Capabilities(2);
+    pub const VERSION_MISMATCH: Capabilities = Capabilities(4);
+    pub const SUPPORTED: Capabilities = Capabilities(7);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+    fn bitand(self, rhs: Self) -> Self {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: usize,
+    pub caps: Capabilities,
+}
+
+impl Handshake {
+    pub fn legacy() -> Handshake {
+        Handshake {
+            version: 0,
+            caps: Capabilities::NONE,
+        }
+    }
+
+    pub fn supports(&self, cap: Capabilities) -> bool {
+        self.caps.contains(cap)
+    }
+}
+
+pub enum Offer {
+    Legacy,
+    Negotiate(usize, Capabilities),
+}
+
+pub async fn read_offer<T: Read + Unpin + Send>(reader: &mut T) -> Result<Option<Offer>> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf).await?;
+    if &buf[..7] != MAGIC_PREFIX {
+        return Ok(None);
+    }
+    match buf[7] {
+        MAGIC_LEGACY => Ok(Some(Offer::Legacy)),
+        MAGIC_NEGOTIATE => {
+            let version = reader.decode_varuint().await?;
+            let caps = reader.decode_varuint().await? as u32;
+            Ok(Some(Offer::Negotiate(version, Capabilities(caps))))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub async fn reply_offer<T: Write + Unpin + Send>(
+    writer: &mut T,
+    offer: Offer,
+) -> Result<Handshake> {
+    match offer {
+        Offer::Legacy => {
+            writer.write_all(b"OK").await?;
+            writer.flush().await?;
+            Ok(Handshake::legacy())
+        }
+        Offer::Negotiate(version, caps) => {
+            if version < MIN_PROTOCOL_VERSION {
+                let reason = format!(
+                    "unsupported protocol version {} (server supports {}..={})",
+                    version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                );
+                writer.write_all(b"NO").await?;
+                writer.encode_binary(reason.as_bytes()).await?;
+                writer.flush().await?;
+                return strerr(reason);
+            }
+            let ret = Handshake {
+                version: version.min(PROTOCOL_VERSION),
+                caps: caps & Capabilities::SUPPORTED,
+            };
+            writer.write_all(b"OK").await?;
+            writer.encode_varuint(ret.version).await?;
+            writer.encode_varuint(ret.caps.0 as usize).await?;
+            writer.flush().await?;
+            Ok(ret)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn negotiate_clamps_version_and_caps() {
+        let mut input: &[u8] = b"MINIBUS\x01\x05\x7f";
+        let offer = read_offer(&mut input).await.unwrap().unwrap();
+        assert!(matches!(offer, Offer::Negotiate(5, Capabilities(0x7f))));
+        let mut output = Vec::new();
+        let ret = reply_offer(&mut output, offer).await.unwrap();
+        assert_eq!(ret.version, PROTOCOL_VERSION);
+        assert_eq!(ret.caps, Capabilities::SUPPORTED);
+        let mut expected = b"OK".to_vec();
+        expected.push(PROTOCOL_VERSION as u8);
+        expected.push(Capabilities::SUPPORTED.0 as u8);
+        assert_eq!(output, expected);
+    }
+
+    #[async_std::test]
+    async fn negotiate_keeps_older_version() {
+        let mut output = Vec::new();
+        let offer = Offer::Negotiate(1, Capabilities::EXTENDED_NEXT);
+        let ret = reply_offer(&mut output, offer).await.unwrap();
+        assert_eq!(ret.version, 1);
+        assert!(ret.supports(Capabilities::EXTENDED_NEXT));
+        assert!(!ret.supports(Capabilities::ERROR_CODES));
+    }
+
+    #[async_std::test]
+    async fn legacy_magic() {
+        let mut input: &[u8] = b"MINIBUS\x00";
+        let offer = read_offer(&mut input).await.unwrap().unwrap();
+        assert!(matches!(offer, Offer::Legacy));
+        let mut output = Vec::new();
+        let ret = reply_offer(&mut output, offer).await.unwrap();
+        assert_eq!(ret, Handshake::legacy());
+        assert_eq!(output, b"OK");
+    }
+
+    #[async_std::test]
+    async fn unknown_magic() {
+        let mut input: &[u8] = b"MINIBUS\x02";
+        assert!(read_offer(&mut input).await.unwrap().is_none());
+        let mut input: &[u8] = b"HTTP/1.1";
+        assert!(read_offer(&mut input).await.unwrap().is_none());
+    }
+
+    #[async_std::test]
+    async fn reject_old_version() {
+        let mut output = Vec::new();
+        let offer = Offer::Negotiate(0, Capabilities::NONE);
+        assert!(reply_offer(&mut output, offer).await.is_err());
+        assert_eq!(&output[..2], b"NO");
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_payload: usize,
+    pub max_subscriptions: usize,
+    pub max_pending_calls: usize,
+    pub max_private_keys: usize,
+    pub call_timeout: Duration,
+}
+
+static mut INSTANCE: Limits = Limits {
+    max_payload: 16 << 20,
+    max_subscriptions: 1024,
+    max_pending_calls: 1024,
+    max_private_keys: 4096,
+    call_timeout: Duration::from_secs(30),
+};
+
+pub fn init(limits: Limits) {
+    unsafe {
+        INSTANCE = limits;
+    }
+}
+
+pub fn get_limits() -> Limits {
+    unsafe { INSTANCE }
+}
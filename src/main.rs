@@ -1,32 +1,105 @@
 use crate::gateway::handle_client;
 use crate::registry::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_std::path::PathBuf;
 use async_std::net;
 use async_std::prelude::*;
 use async_std::task;
+use futures_rustls::TlsAcceptor;
 use pretty_env_logger;
+use std::time::Duration;
 use structopt::StructOpt;
 
+mod auth;
 mod broker;
+mod descriptor;
 mod entity;
+mod error;
 mod gateway;
+mod group;
+mod handshake;
+mod limits;
 mod packet;
+mod persist;
+mod policy;
 mod registry;
 mod shared;
 mod short_text;
+mod tls;
+#[cfg(unix)]
+mod unix;
 mod utils;
 mod webgateway;
+mod websocket;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "minibus", about = "MiniBus Server Implemention")]
 struct Opt {
     #[structopt(short, long, default_value = "127.0.0.1:4040")]
     listen: String,
+    #[structopt(long = "no-tcp")]
+    no_tcp: bool,
+    #[cfg(unix)]
+    #[structopt(long = "unix", parse(from_os_str))]
+    unix: Option<PathBuf>,
+    #[cfg(unix)]
+    #[structopt(long = "unix-mode", parse(try_from_str = unix::parse_mode), requires = "unix")]
+    unix_mode: Option<u32>,
+    #[cfg(unix)]
+    #[structopt(long = "unix-owner", parse(try_from_str = unix::parse_owner), requires = "unix")]
+    unix_owner: Option<(u32, Option<u32>)>,
 
     #[structopt(long = "webapi", default_value = "0.0.0.0:8234")]
     webapi: String,
     #[structopt(long = "webbase", default_value = "/")]
     webbase: String,
+
+    #[structopt(long = "tls-cert", parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    #[structopt(long = "tls-client-ca", parse(from_os_str), requires = "tls-cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(long = "auth-tokens", parse(from_os_str), conflicts_with = "auth-secrets")]
+    auth_tokens: Option<PathBuf>,
+    #[structopt(long = "auth-secrets", parse(from_os_str))]
+    auth_secrets: Option<PathBuf>,
+    #[structopt(long = "policy", parse(from_os_str))]
+    policy: Option<PathBuf>,
+
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    #[structopt(long = "fsync", default_value = "interval", possible_values = &["always", "interval", "never"])]
+    fsync: persist::FsyncPolicy,
+    #[structopt(long = "snapshot-interval", default_value = "300")]
+    snapshot_interval: u64,
+
+    #[structopt(long = "max-payload", default_value = "16777216")]
+    max_payload: usize,
+    #[structopt(long = "max-subscriptions", default_value = "1024")]
+    max_subscriptions: usize,
+    #[structopt(long = "max-pending-calls", default_value = "1024")]
+    max_pending_calls: usize,
+    #[structopt(long = "max-private-keys", default_value = "4096")]
+    max_private_keys: usize,
+    #[structopt(long = "call-timeout", default_value = "30000")]
+    call_timeout: u64,
+
+    #[structopt(long = "group-policy", default_value = "round-robin", possible_values = &["round-robin", "random", "least-pending"])]
+    group_policy: group::GroupPolicy,
+}
+
+impl Opt {
+    #[cfg(unix)]
+    fn has_unix(&self) -> bool {
+        self.unix.is_some()
+    }
+
+    #[cfg(not(unix))]
+    fn has_unix(&self) -> bool {
+        false
+    }
 }
 
 #[async_std::main]
@@ -34,16 +107,85 @@ async fn main() -> Result<()> {
     let opt = Opt::from_args();
     pretty_env_logger::init();
     log::info!("option: {:#?}", &opt);
-    Registry::init().await;
+    if opt.no_tcp && !opt.has_unix() {
+        return Err(anyhow!("--no-tcp requires another listener such as --unix"));
+    }
+    limits::init(limits::Limits {
+        max_payload: opt.max_payload,
+        max_subscriptions: opt.max_subscriptions,
+        max_pending_calls: opt.max_pending_calls,
+        max_private_keys: opt.max_private_keys,
+        call_timeout: Duration::from_millis(opt.call_timeout),
+    });
+    if let Some(path) = &opt.auth_tokens {
+        auth::init(Some(Box::new(auth::TokenFile::load(path).await?)));
+    } else if let Some(path) = &opt.auth_secrets {
+        auth::init(Some(Box::new(auth::SharedSecret::load(path).await?)));
+    }
+    if let Some(path) = &opt.policy {
+        policy::init(Some(policy::Policy::load(path).await?));
+    }
+    if let Some(dir) = &opt.data_dir {
+        persist::init(
+            dir.to_owned(),
+            opt.fsync,
+            Duration::from_secs(opt.snapshot_interval),
+        )
+        .await?;
+    }
+    Registry::init(opt.group_policy).await?;
+
+    let acceptor = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(tls::load_acceptor(cert, key, opt.tls_client_ca.as_deref()).await?)
+        }
+        _ => None,
+    };
 
     let mut app = tide::new();
     webgateway::init(&mut app.at(&opt.webbase));
-    task::spawn(app.listen(opt.webapi));
+    task::spawn(webgateway::listen(
+        app,
+        opt.webapi,
+        acceptor.clone(),
+        webgateway::websocket_path(&opt.webbase),
+    ));
+
+    let mut listeners = Vec::new();
+    #[cfg(unix)]
+    {
+        if let Some(path) = opt.unix {
+            let (mode, owner) = (opt.unix_mode, opt.unix_owner);
+            listeners.push(task::spawn(
+                async move { unix::serve(&path, mode, owner).await },
+            ));
+        }
+    }
+    if !opt.no_tcp {
+        listeners.push(task::spawn(serve_tcp(opt.listen, acceptor)));
+    }
+    // the first listener to fail ends the process rather than leaving the others serving alone
+    futures_util::future::try_join_all(listeners).await?;
+    Ok(())
+}
 
-    let listener = net::TcpListener::bind(opt.listen).await?;
+async fn serve_tcp(addr: String, acceptor: Option<TlsAcceptor>) -> std::io::Result<()> {
+    let listener = net::TcpListener::bind(addr).await?;
     let mut incoming = listener.incoming();
     while let Some(stream) = incoming.next().await {
-        task::spawn(handle_client(stream?));
+        let stream = stream?;
+        match &acceptor {
+            Some(acceptor) => {
+                let acceptor = acceptor.clone();
+                task::spawn(async move {
+                    match tls::accept(&acceptor, stream).await {
+                        Ok((stream, peer)) => handle_client(stream, peer).await,
+                        Err(e) => Err(e.into()),
+                    }
+                })
+            }
+            None => task::spawn(handle_client(stream, None)),
+        };
     }
     Ok(())
 }
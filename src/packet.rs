@@ -1,3 +1,4 @@
+use crate::error::BusError;
 use crate::short_text::ShortText;
 use crate::utils::*;
 use async_std::io::{Error, ErrorKind, Read, ReadExt, Result, Write};
@@ -41,6 +42,7 @@ pub enum ResponseKind {
     RESP,
     NEXT,
     CALL,
+    CANCEL,
 }
 
 #[async_trait]
@@ -52,6 +54,7 @@ impl<T: Read + Unpin + Send> Decoder<ResponseKind> for T {
             b"RESP" => ResponseKind::RESP,
             b"NEXT" => ResponseKind::NEXT,
             b"CALL" => ResponseKind::CALL,
+            b"CANC" => ResponseKind::CANCEL,
             _ => return Err(Error::new(ErrorKind::Other, "not match")),
         };
         Ok(ret)
@@ -65,6 +68,7 @@ impl<T: Write + Unpin + Send> Encoder<ResponseKind> for T {
             ResponseKind::RESP => b"RESP",
             ResponseKind::NEXT => b"NEXT",
             ResponseKind::CALL => b"CALL",
+            ResponseKind::CANCEL => b"CANC",
         };
         self.write(data).await?;
         Ok(())
@@ -75,22 +79,39 @@ impl<T: Write + Unpin + Send> Encoder<ResponseKind> for T {
 pub enum ResponsePayload {
     Success,
     SuccessWithData(Vec<u8>),
-    Failed(Vec<u8>),
+    Mismatch(u64),
+    Failed(BusError),
+}
+
+async fn decode_payload<T: Read + Unpin + Send>(
+    reader: &mut T,
+    coded: bool,
+) -> Result<ResponsePayload> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf).await?;
+    let typ = buf[0];
+    let ret = match typ {
+        0 => ResponsePayload::Success,
+        1 => ResponsePayload::SuccessWithData(reader.decode_binary().await?),
+        254 => ResponsePayload::Mismatch(reader.decode_varuint().await? as u64),
+        255 => {
+            let data = reader.decode_binary().await?;
+            if coded {
+                ResponsePayload::Failed(BusError::decode(&data).await)
+            } else {
+                let message = String::from_utf8_lossy(&data).into_owned();
+                ResponsePayload::Failed(BusError::Remote(message))
+            }
+        }
+        _ => return Err(Error::new(ErrorKind::Other, "not match")),
+    };
+    Ok(ret)
 }
 
 #[async_trait]
 impl<T: Read + Unpin + Send> Decoder<ResponsePayload> for T {
     async fn decode(&mut self) -> Result<ResponsePayload> {
-        let mut buf = [0u8; 1];
-        self.read_exact(&mut buf).await?;
-        let typ = buf[0];
-        let ret = match typ {
-            0 => ResponsePayload::Success,
-            1 => ResponsePayload::SuccessWithData(self.decode_binary().await?),
-            255 => ResponsePayload::Failed(self.decode_binary().await?),
-            _ => return Err(Error::new(ErrorKind::Other, "not match")),
-        };
-        Ok(ret)
+        decode_payload(self, false).await
     }
 }
 
@@ -105,15 +126,49 @@ impl<T: Write + Unpin + Send> Encoder<ResponsePayload> for T {
                 self.write(&[1]).await?;
                 self.encode_binary(&data).await?;
             }
-            ResponsePayload::Failed(data) => {
+            ResponsePayload::Mismatch(version) => {
+                self.write(&[254]).await?;
+                self.encode_varuint(version as usize).await?;
+            }
+            ResponsePayload::Failed(e) => {
                 self.write(&[255]).await?;
-                self.encode_binary(&data).await?;
+                self.encode_binary(e.message().as_bytes()).await?;
             }
         };
         Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum EventKind {
+    Set,
+    Delete,
+    Notify,
+}
+
+impl EventKind {
+    pub fn of(data: Option<&[u8]>) -> EventKind {
+        if data.is_some() {
+            EventKind::Set
+        } else {
+            EventKind::Delete
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Write + Unpin + Send> Encoder<EventKind> for T {
+    async fn encode(&mut self, data: EventKind) -> Result<()> {
+        let data = match data {
+            EventKind::Set => 0,
+            EventKind::Delete => 1,
+            EventKind::Notify => 2,
+        };
+        self.write(&[data]).await?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Response {
     pub reqid: u32,
@@ -143,6 +198,13 @@ impl Response {
             payload,
         }
     }
+    pub fn new_cancel(reqid: u32, payload: ResponsePayload) -> Response {
+        Response {
+            reqid,
+            kind: ResponseKind::CANCEL,
+            payload,
+        }
+    }
 }
 
 #[async_trait]
@@ -170,3 +232,86 @@ impl<T: Write + Unpin + Send> Encoder<Response> for T {
         Ok(())
     }
 }
+
+// failures carry the error code before the message, for clients with ERROR_CODES
+pub struct CodedResponse(pub Response);
+
+#[async_trait]
+impl<T: Write + Unpin + Send> Encoder<CodedResponse> for T {
+    async fn encode(&mut self, data: CodedResponse) -> Result<()> {
+        match data.0 {
+            Response {
+                reqid,
+                kind,
+                payload: ResponsePayload::Failed(e),
+            } => {
+                debug!("response: {} {:?} {:?}", reqid, kind, e);
+                self.encode_reqid(reqid).await?;
+                self.encode(kind).await?;
+                self.write(&[255]).await?;
+                self.encode_binary(&e.encode().await).await?;
+            }
+            resp => self.encode(resp).await?,
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Read + Unpin + Send> Decoder<CodedResponse> for T {
+    async fn decode(&mut self) -> Result<CodedResponse> {
+        let reqid = self.decode_reqid().await?;
+        let kind = self.decode().await?;
+        let payload = decode_payload(self, true).await?;
+        Ok(CodedResponse(Response {
+            reqid,
+            kind,
+            payload,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed() -> Response {
+        Response::new_resp(
+            7,
+            ResponsePayload::Failed(BusError::Forbidden("denied".to_owned())),
+        )
+    }
+
+    #[async_std::test]
+    async fn coded_round_trip() {
+        let mut buf = Vec::new();
+        buf.encode(CodedResponse(failed())).await.unwrap();
+        let CodedResponse(res): CodedResponse = buf.as_slice().decode().await.unwrap();
+        assert_eq!(res.reqid, 7);
+        assert!(matches!(res.kind, ResponseKind::RESP));
+        match res.payload {
+            ResponsePayload::Failed(e) => assert_eq!(e, BusError::Forbidden("denied".to_owned())),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut buf = Vec::new();
+        let success = ResponsePayload::SuccessWithData(b"data".to_vec());
+        buf.encode(CodedResponse(Response::new_next(8, success)))
+            .await
+            .unwrap();
+        let CodedResponse(res): CodedResponse = buf.as_slice().decode().await.unwrap();
+        assert!(matches!(res.kind, ResponseKind::NEXT));
+        assert!(matches!(res.payload, ResponsePayload::SuccessWithData(x) if x == b"data"));
+    }
+
+    #[async_std::test]
+    async fn legacy_failure_is_remote() {
+        let mut buf = Vec::new();
+        buf.encode(failed()).await.unwrap();
+        let res: Response = buf.as_slice().decode().await.unwrap();
+        match res.payload {
+            ResponsePayload::Failed(e) => assert_eq!(e, BusError::Remote("denied".to_owned())),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::short_text::ShortText;
+use crate::utils::{strerr, DecoderUtils, EncoderUtils};
+use async_std::fs::{self, File, OpenOptions};
+use async_std::io::{Error, ErrorKind, Result};
+use async_std::path::PathBuf;
+use async_std::prelude::*;
+use async_std::sync::Mutex;
+use async_std::task;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    Interval,
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "interval" => Ok(FsyncPolicy::Interval),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("unknown fsync policy: {}", s)),
+        }
+    }
+}
+
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+pub enum Record {
+    Set(ShortText, Vec<u8>, Option<Instant>, u64),
+    Del(ShortText),
+    // highest version handed out, so a restart never reuses one
+    Revision(u64),
+}
+
+fn to_unix_millis(deadline: Option<Instant>) -> usize {
+    match deadline {
+        Some(deadline) => {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let at = SystemTime::now() + left;
+            at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as usize
+        }
+        None => 0,
+    }
+}
+
+fn from_unix_millis(millis: usize) -> Option<Instant> {
+    let at = UNIX_EPOCH.checked_add(Duration::from_millis(millis as u64))?;
+    let left = at.duration_since(SystemTime::now()).unwrap_or_default();
+    Instant::now().checked_add(left)
+}
+
+async fn encode_record(buf: &mut Vec<u8>, record: &Record) -> Result<()> {
+    match record {
+        Record::Set(key, value, deadline, version) => {
+            buf.push(0);
+            buf.encode_short_text(key).await?;
+            buf.encode_varuint(to_unix_millis(*deadline)).await?;
+            buf.encode_varuint(*version as usize).await?;
+            buf.encode_binary(value).await?;
+        }
+        Record::Del(key) => {
+            buf.push(1);
+            buf.encode_short_text(key).await?;
+        }
+        Record::Revision(version) => {
+            buf.push(2);
+            buf.encode_varuint(*version as usize).await?;
+        }
+    }
+    Ok(())
+}
+
+// the journal is already in memory, so values are not bound by the network payload cap
+async fn decode_value(payload: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = payload.decode_varuint().await?;
+    if len > payload.len() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated value"));
+    }
+    let (value, rest) = payload.split_at(len);
+    *payload = rest;
+    Ok(value.to_vec())
+}
+
+// Ok(None) for a well-formed record that cannot be restored
+async fn decode_record(payload: &mut &[u8]) -> Result<Option<Record>> {
+    let (op, rest) = match payload.split_first() {
+        Some((op, rest)) => (*op, rest),
+        None => return strerr("empty record"),
+    };
+    *payload = rest;
+    match op {
+        0 => {
+            let key = payload.decode_short_text().await?;
+            let millis = payload.decode_varuint().await?;
+            let version = payload.decode_varuint().await? as u64;
+            let value = decode_value(payload).await?;
+            let deadline = match millis {
+                0 => None,
+                millis => match from_unix_millis(millis) {
+                    Some(deadline) => Some(deadline),
+                    None => {
+                        log::warn!("skipping {}: deadline out of range", key);
+                        return Ok(None);
+                    }
+                },
+            };
+            Ok(Some(Record::Set(key, value, deadline, version)))
+        }
+        1 => Ok(Some(Record::Del(payload.decode_short_text().await?))),
+        2 => {
+            let version = payload.decode_varuint().await? as u64;
+            Ok(Some(Record::Revision(version)))
+        }
+        _ => strerr("unknown record"),
+    }
+}
+
+async fn read_records(path: &PathBuf, ret: &mut Vec<Record>) -> Result<()> {
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut payload = content.as_slice();
+    while !payload.is_empty() {
+        match decode_record(&mut payload).await {
+            Ok(Some(record)) => ret.push(record),
+            Ok(None) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                log::warn!("{}: dropping truncated tail ({})", path.display(), e);
+                break;
+            }
+            Err(e) => return strerr(format!("{}: corrupt record ({})", path.display(), e)),
+        }
+    }
+    Ok(())
+}
+
+struct JournalState {
+    log: File,
+    dirty: bool,
+    last_snapshot: Instant,
+}
+
+pub struct Journal {
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    policy: FsyncPolicy,
+    snapshot_interval: Duration,
+    state: Mutex<JournalState>,
+}
+
+impl Journal {
+    async fn open(
+        dir: PathBuf,
+        name: &str,
+        policy: FsyncPolicy,
+        snapshot_interval: Duration,
+    ) -> Result<Journal> {
+        fs::create_dir_all(&dir).await?;
+        let snapshot_path = dir.join(format!("{}.snapshot", name));
+        let log_path = dir.join(format!("{}.log", name));
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .await?;
+        Ok(Journal {
+            snapshot_path,
+            log_path,
+            policy,
+            snapshot_interval,
+            state: Mutex::new(JournalState {
+                log,
+                dirty: false,
+                last_snapshot: Instant::now(),
+            }),
+        })
+    }
+
+    pub async fn load(&self) -> Result<Vec<Record>> {
+        let mut ret = Vec::new();
+        read_records(&self.snapshot_path, &mut ret).await?;
+        read_records(&self.log_path, &mut ret).await?;
+        Ok(ret)
+    }
+
+    pub async fn append(&self, record: &Record) -> Result<()> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, record).await?;
+        let mut state = self.state.lock().await;
+        state.log.write_all(&buf).await?;
+        state.log.flush().await?;
+        if self.policy == FsyncPolicy::Always {
+            state.log.sync_data().await?;
+        } else {
+            state.dirty = true;
+        }
+        Ok(())
+    }
+
+    pub async fn snapshot_due(&self) -> bool {
+        let state = self.state.lock().await;
+        state.last_snapshot.elapsed() >= self.snapshot_interval
+    }
+
+    pub async fn snapshot<I: Iterator<Item = Record>>(&self, records: I) -> Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            encode_record(&mut buf, &record).await?;
+        }
+        let mut state = self.state.lock().await;
+        let temp_path = self.snapshot_path.with_extension("snapshot.tmp");
+        let mut temp = File::create(&temp_path).await?;
+        temp.write_all(&buf).await?;
+        temp.sync_all().await?;
+        drop(temp);
+        fs::rename(&temp_path, &self.snapshot_path).await?;
+        state.log.set_len(0).await?;
+        state.log.sync_all().await?;
+        state.dirty = false;
+        state.last_snapshot = Instant::now();
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if state.dirty {
+            state.log.sync_data().await?;
+            state.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+static mut INSTANCE: Option<Journal> = None;
+
+pub async fn init(
+    dir: PathBuf,
+    policy: FsyncPolicy,
+    snapshot_interval: Duration,
+) -> Result<()> {
+    let journal = Journal::open(dir, "shared", policy, snapshot_interval).await?;
+    unsafe {
+        INSTANCE = Some(journal);
+    }
+    if policy == FsyncPolicy::Interval {
+        task::spawn(async {
+            loop {
+                task::sleep(FSYNC_INTERVAL).await;
+                if let Err(e) = get_shared_journal().unwrap().sync().await {
+                    log::error!("failed to sync journal: {}", e);
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+pub fn get_shared_journal() -> Option<&'static Journal> {
+    unsafe { INSTANCE.as_ref() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encode(record: &Record) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, record).await.unwrap();
+        buf
+    }
+
+    #[async_std::test]
+    async fn record_round_trip() {
+        let key = ShortText::build(b"a");
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut buf = encode(&Record::Set(key, b"x".to_vec(), Some(deadline), 3)).await;
+        buf.extend(encode(&Record::Set(key, b"y".to_vec(), None, 4)).await);
+        buf.extend(encode(&Record::Del(key)).await);
+        buf.extend(encode(&Record::Revision(9)).await);
+        let mut payload = buf.as_slice();
+        match decode_record(&mut payload).await.unwrap() {
+            Some(Record::Set(k, v, Some(d), 3)) => {
+                assert_eq!(k, key);
+                assert_eq!(v, b"x");
+                assert!(d.saturating_duration_since(deadline) < Duration::from_secs(1));
+                assert!(deadline.saturating_duration_since(d) < Duration::from_secs(1));
+            }
+            _ => panic!("expected set with deadline"),
+        }
+        assert!(matches!(
+            decode_record(&mut payload).await.unwrap(),
+            Some(Record::Set(_, v, None, 4)) if v == b"y"
+        ));
+        assert!(matches!(
+            decode_record(&mut payload).await.unwrap(),
+            Some(Record::Del(k)) if k == key
+        ));
+        assert!(matches!(
+            decode_record(&mut payload).await.unwrap(),
+            Some(Record::Revision(9))
+        ));
+        assert!(payload.is_empty());
+    }
+
+    #[async_std::test]
+    async fn value_above_network_cap() {
+        let value = vec![7u8; crate::limits::get_limits().max_payload + 1];
+        let buf = encode(&Record::Set(ShortText::build(b"a"), value.clone(), None, 1)).await;
+        let mut payload = buf.as_slice();
+        assert!(matches!(
+            decode_record(&mut payload).await.unwrap(),
+            Some(Record::Set(_, v, None, 1)) if v == value
+        ));
+    }
+
+    async fn read_file(name: &str, content: &[u8]) -> Result<Vec<Record>> {
+        let name = format!("minibus-{}-{}", name, std::process::id());
+        let path = PathBuf::from(std::env::temp_dir()).join(name);
+        fs::write(&path, content).await.unwrap();
+        let mut ret = Vec::new();
+        let res = read_records(&path, &mut ret).await;
+        fs::remove_file(&path).await.unwrap();
+        res.map(|_| ret)
+    }
+
+    #[async_std::test]
+    async fn truncated_tail() {
+        let mut buf = encode(&Record::Del(ShortText::build(b"a"))).await;
+        let record = Record::Set(ShortText::build(b"b"), b"xyz".to_vec(), None, 1);
+        let tail = encode(&record).await;
+        buf.extend_from_slice(&tail[..tail.len() - 1]);
+        let records = read_file("truncated", &buf).await.unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn corrupt_record() {
+        let mut buf = encode(&Record::Del(ShortText::build(b"a"))).await;
+        buf.extend_from_slice(b"\x07garbage");
+        assert!(read_file("corrupt", &buf).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn hostile_deadline() {
+        let mut buf = vec![0];
+        let key = ShortText::build(b"a");
+        buf.encode_short_text(&key).await.unwrap();
+        buf.encode_varuint(usize::MAX).await.unwrap();
+        buf.encode_varuint(1).await.unwrap();
+        buf.encode_binary(b"x").await.unwrap();
+        buf.extend(encode(&Record::Del(ShortText::build(b"b"))).await);
+        let mut payload = buf.as_slice();
+        decode_record(&mut payload).await.unwrap();
+        assert!(matches!(
+            decode_record(&mut payload).await.unwrap(),
+            Some(Record::Del(_))
+        ));
+    }
+}
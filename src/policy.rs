@@ -0,0 +1,222 @@
+use crate::broker::EventKey;
+use crate::error::{BusError, BusResult};
+use crate::short_text::ShortText;
+use crate::utils::strerr;
+use async_std::fs;
+use async_std::io::Result;
+use async_std::path::Path;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Set,
+    Get,
+    Call,
+    Notify,
+    Listen,
+    Observe,
+    Name,
+}
+
+impl FromStr for Permission {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "SET" => Ok(Permission::Set),
+            "GET" => Ok(Permission::Get),
+            "CALL" => Ok(Permission::Call),
+            "NOTIFY" => Ok(Permission::Notify),
+            "LISTEN" => Ok(Permission::Listen),
+            "OBSERVE" => Ok(Permission::Observe),
+            "NAME" => Ok(Permission::Name),
+            _ => Err(format!("unknown permission: {}", s)),
+        }
+    }
+}
+
+struct Rule {
+    role: String,
+    permission: Option<Permission>,
+    pattern: EventKey,
+}
+
+// role <name> <member>...  ("*" is anyone, "+" is any authenticated peer)
+// allow <role> <permission|*> <entity> <key>
+pub struct Policy {
+    roles: HashMap<String, Vec<String>>,
+    rules: Vec<Rule>,
+}
+
+fn parse_text(text: &str) -> Option<ShortText> {
+    text.parse().ok()
+}
+
+impl Policy {
+    pub async fn load(path: &Path) -> Result<Policy> {
+        let content = fs::read_to_string(path).await?;
+        let mut roles: HashMap<String, Vec<String>> = HashMap::new();
+        let mut rules = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<_> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                ["role", name, members @ ..] => roles
+                    .entry((*name).to_owned())
+                    .or_default()
+                    .extend(members.iter().map(|x| (*x).to_owned())),
+                ["allow", role, permission, entity, key] => {
+                    let permission = match *permission {
+                        "*" => None,
+                        permission => match permission.parse() {
+                            Ok(permission) => Some(permission),
+                            Err(e) => return strerr(e),
+                        },
+                    };
+                    let pattern = match (parse_text(entity), parse_text(key)) {
+                        (Some(entity), Some(key)) => EventKey(entity, key),
+                        _ => return strerr(format!("pattern too long: {}", line)),
+                    };
+                    rules.push(Rule {
+                        role: (*role).to_owned(),
+                        permission,
+                        pattern,
+                    });
+                }
+                _ => return strerr(format!("malformed line in {}: {}", path.display(), line)),
+            }
+        }
+        for rule in &rules {
+            if !roles.contains_key(&rule.role) {
+                return strerr(format!("undefined role: {}", rule.role));
+            }
+        }
+        Ok(Policy { roles, rules })
+    }
+
+    fn has_role(&self, identity: Option<&str>, role: &str) -> bool {
+        match self.roles.get(role) {
+            Some(members) => members.iter().any(|member| match (member.as_str(), identity) {
+                ("*", _) => true,
+                ("+", Some(_)) => true,
+                (member, Some(identity)) => member == identity,
+                _ => false,
+            }),
+            None => false,
+        }
+    }
+
+    pub fn check(
+        &self,
+        identity: Option<&str>,
+        permission: Permission,
+        entity: &ShortText,
+        key: &ShortText,
+    ) -> BusResult<()> {
+        let target = EventKey(entity.to_owned(), key.to_owned());
+        let allowed = self.rules.iter().any(|rule| {
+            (rule.permission.is_none() || rule.permission == Some(permission))
+                && rule.pattern.matches(&target)
+                && self.has_role(identity, &rule.role)
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(BusError::Forbidden("permission denied".to_owned()))
+        }
+    }
+}
+
+// writing to the registry registers a name, so it is checked as such
+pub fn write_permission(entity: &ShortText) -> Permission {
+    if entity.as_bytes() == b"registry" {
+        Permission::Name
+    } else {
+        Permission::Set
+    }
+}
+
+static mut INSTANCE: Option<Policy> = None;
+
+pub fn init(policy: Option<Policy>) {
+    unsafe {
+        INSTANCE = policy;
+    }
+}
+
+pub fn check(
+    identity: Option<&str>,
+    permission: Permission,
+    entity: &ShortText,
+    key: &ShortText,
+) -> BusResult<()> {
+    match unsafe { INSTANCE.as_ref() } {
+        Some(policy) => policy.check(identity, permission, entity, key),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::path::PathBuf;
+
+    async fn load(name: &str, content: &str) -> Result<Policy> {
+        let name = format!("minibus-policy-{}-{}", name, std::process::id());
+        let path = PathBuf::from(std::env::temp_dir()).join(name);
+        fs::write(&path, content).await.unwrap();
+        let ret = Policy::load(&path).await;
+        fs::remove_file(&path).await.unwrap();
+        ret
+    }
+
+    fn text(text: &str) -> ShortText {
+        text.parse().unwrap()
+    }
+
+    const POLICY: &str = "
+# comment
+role admin root
+role users +
+role anyone *
+allow admin * * *
+allow users GET shared *
+allow users SET shared user.*
+allow anyone OBSERVE shared public
+";
+
+    #[async_std::test]
+    async fn check_rules() {
+        let policy = load("rules", POLICY).await.unwrap();
+        let check = |identity, permission, entity, key| {
+            policy.check(identity, permission, &text(entity), &text(key))
+        };
+        assert!(check(Some("root"), Permission::Name, "registry", "x").is_ok());
+        assert!(check(Some("alice"), Permission::Get, "shared", "a").is_ok());
+        assert!(check(Some("alice"), Permission::Set, "shared", "user.a").is_ok());
+        assert!(matches!(
+            check(Some("alice"), Permission::Set, "shared", "a"),
+            Err(BusError::Forbidden(_))
+        ));
+        assert!(check(None, Permission::Get, "shared", "a").is_err());
+        assert!(check(None, Permission::Observe, "shared", "public").is_ok());
+        assert!(check(None, Permission::Observe, "shared", "other").is_err());
+    }
+
+    #[test]
+    fn registry_writes_need_name() {
+        assert_eq!(write_permission(&text("registry")), Permission::Name);
+        assert_eq!(write_permission(&text("shared")), Permission::Set);
+    }
+
+    #[async_std::test]
+    async fn reject_bad_files() {
+        assert!(load("undefined", "allow ghost * * *").await.is_err());
+        assert!(load("malformed", "role a b\nallow a *").await.is_err());
+        assert!(load("perm", "role a b\nallow a PUT * *").await.is_err());
+        assert!(load("empty", "").await.is_ok());
+    }
+}
@@ -1,16 +1,24 @@
-use crate::broker::{get_event_broker, EventKey};
+use crate::broker::{get_event_broker, EventKey, Snapshot};
+use crate::descriptor::Descriptor;
 use crate::entity::{AccessTag, Entity};
+use crate::error::{BusError, BusResult};
+use crate::group::{GroupPolicy, ServiceGroup};
 use crate::short_text::ShortText;
-use crate::utils::strerr;
 use async_std::io::Result;
 use async_std::sync::{Arc, Mutex, Weak};
+use async_std::task;
 use async_trait::async_trait;
+use std::collections::HashMap;
 use std::ptr;
-use weak_table::{PtrWeakKeyHashMap, WeakValueHashMap};
+use std::time::{Duration, Instant};
+use weak_table::{PtrWeakHashSet, PtrWeakKeyHashMap, WeakValueHashMap};
 
 struct BidiMap {
     forward: WeakValueHashMap<ShortText, Weak<dyn Entity>>,
-    reverse: PtrWeakKeyHashMap<Weak<dyn Entity>, ShortText>,
+    reverse: PtrWeakKeyHashMap<Weak<dyn Entity>, Vec<ShortText>>,
+    groups: HashMap<ShortText, Arc<ServiceGroup>>,
+    descriptors: HashMap<ShortText, (Vec<u8>, Option<Descriptor>)>,
+    callees: PtrWeakHashSet<Weak<dyn Entity>>,
 }
 
 impl BidiMap {
@@ -18,6 +26,9 @@ impl BidiMap {
         BidiMap {
             forward: WeakValueHashMap::new(),
             reverse: PtrWeakKeyHashMap::new(),
+            groups: HashMap::new(),
+            descriptors: HashMap::new(),
+            callees: PtrWeakHashSet::new(),
         }
     }
 }
@@ -28,15 +39,19 @@ inventory::collect!(StaticRegistryItem);
 
 pub struct Registry {
     entities: Mutex<BidiMap>,
+    policy: GroupPolicy,
 }
 
 static mut INSTANCE: Option<Arc<Registry>> = None;
 
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(250);
+
 impl Registry {
-    pub async fn init() {
+    pub async fn init(policy: GroupPolicy) -> Result<()> {
         let instance = unsafe {
             INSTANCE.replace(Arc::new(Registry {
                 entities: Mutex::new(BidiMap::new()),
+                policy,
             }));
             INSTANCE.as_ref().unwrap()
         };
@@ -45,8 +60,130 @@ impl Registry {
             .forward
             .insert(ShortText::build(b"registry"), instance.clone());
         for StaticRegistryItem(key, entity) in inventory::iter {
+            entity.restore().await?;
             guard.forward.insert(ShortText::build(key), entity.clone());
         }
+        task::spawn(async {
+            loop {
+                task::sleep(EXPIRE_INTERVAL).await;
+                Registry::get_global().expire_all().await;
+            }
+        });
+        Ok(())
+    }
+    async fn expire_all(&self) {
+        let entities: Vec<_> = {
+            let guard = self.entities.lock().await;
+            let mut entities: Vec<_> = guard.forward.values().collect();
+            for group in guard.groups.values() {
+                entities.extend(group.members().await);
+            }
+            entities.extend(guard.callees.iter());
+            entities
+        };
+        // expiry notifies peers, so one that stopped reading must not hold up the others
+        let now = Instant::now();
+        for (idx, entity) in entities.iter().enumerate() {
+            if entities[..idx].iter().any(|x| Arc::ptr_eq(x, entity)) {
+                continue;
+            }
+            let entity = Arc::clone(entity);
+            task::spawn(async move { entity.expire(now).await });
+        }
+        let mut guard = self.entities.lock().await;
+        let BidiMap {
+            forward,
+            descriptors,
+            ..
+        } = &mut *guard;
+        descriptors.retain(|name, _| forward.contains_key(name));
+        let mut empty = Vec::new();
+        for (name, group) in guard.groups.iter() {
+            if group.is_empty().await {
+                empty.push(name.to_owned());
+            }
+        }
+        for name in empty {
+            guard.groups.remove(&name);
+            guard.forward.remove(&name);
+            get_event_broker()
+                .send(EventKey(ShortText::build(b"registry"), name), None)
+                .await;
+        }
+    }
+    // a callee may drop its names while calls are outstanding, so expiry keeps visiting it
+    pub async fn track_callee(&self, callee: &Arc<dyn Entity>) {
+        let mut guard = self.entities.lock().await;
+        guard.callees.insert(Arc::clone(callee));
+    }
+    pub async fn check_call(
+        &self,
+        name: &ShortText,
+        method: &ShortText,
+        data: &[u8],
+    ) -> BusResult<()> {
+        let guard = self.entities.lock().await;
+        match guard.descriptors.get(name) {
+            Some((_, Some(descriptor))) => descriptor.check_call(method, data),
+            _ => Ok(()),
+        }
+    }
+    pub async fn descriptors(&self) -> Vec<(ShortText, Descriptor)> {
+        let guard = self.entities.lock().await;
+        let mut ret: Vec<_> = guard
+            .descriptors
+            .iter()
+            .filter(|(name, _)| guard.forward.contains_key(*name))
+            .filter_map(|(name, (_, descriptor))| {
+                descriptor.to_owned().map(|x| (name.to_owned(), x))
+            })
+            .collect();
+        ret.sort_by_key(|x| x.0);
+        ret
+    }
+    pub async fn join(&self, sender: &Arc<dyn Entity>, name: &ShortText) -> BusResult<()> {
+        if !sender.can_own_names() {
+            return Err(BusError::NotSupported("not supported".to_owned()));
+        }
+        let mut guard = self.entities.lock().await;
+        if let Some(group) = guard.groups.get(name) {
+            return group.join(sender).await;
+        }
+        if name.contains('*') {
+            return Err(BusError::Malformed("invalid name".to_owned()));
+        } else if guard.forward.contains_key(name) {
+            return Err(BusError::Duplicate("duplicated".to_owned()));
+        }
+        let group = Arc::new(ServiceGroup::new(self.policy));
+        group.join(sender).await?;
+        guard.forward.insert(name.to_owned(), group.clone());
+        guard.groups.insert(name.to_owned(), group);
+        get_event_broker()
+            .send(
+                EventKey(ShortText::build(b"registry"), name.to_owned()),
+                Some(&[]),
+            )
+            .await;
+        Ok(())
+    }
+    pub async fn leave(&self, sender: &Arc<dyn Entity>, name: &ShortText) -> BusResult<()> {
+        let mut guard = self.entities.lock().await;
+        let group = match guard.groups.get(name) {
+            Some(group) => group.clone(),
+            None => return Err(BusError::NotFound("not found".to_owned())),
+        };
+        group.leave(sender).await?;
+        if group.is_empty().await {
+            guard.groups.remove(name);
+            guard.forward.remove(name);
+            get_event_broker()
+                .send(
+                    EventKey(ShortText::build(b"registry"), name.to_owned()),
+                    None,
+                )
+                .await;
+        }
+        Ok(())
     }
     pub fn get_global() -> &'static Registry {
         unsafe { INSTANCE.as_ref().unwrap() }
@@ -59,6 +196,43 @@ impl Registry {
             None
         }
     }
+    pub async fn snapshot(&self, sender: Option<&Arc<dyn Entity>>, pattern: &EventKey) -> Snapshot {
+        let targets: Vec<_> = if pattern.0.ends_with('*') {
+            let guard = self.entities.lock().await;
+            guard
+                .forward
+                .iter()
+                .filter(|(name, _)| pattern.matches(&EventKey((*name).to_owned(), pattern.1)))
+                .map(|(name, entity)| (name.to_owned(), entity))
+                .collect()
+        } else {
+            self.find(&pattern.0)
+                .await
+                .map(|entity| (pattern.0, entity))
+                .into_iter()
+                .collect()
+        };
+        let mut ret = Vec::new();
+        for (name, entity) in targets {
+            if pattern.1.ends_with('*') {
+                let keys = entity.keys(sender).await.unwrap_or_default();
+                for (key, tag) in keys {
+                    let ek = EventKey(name, key);
+                    if tag == AccessTag::Private || !pattern.matches(&ek) {
+                        continue;
+                    }
+                    if let Ok(value) = entity.get(sender, &ek.1).await {
+                        ret.push((ek, value));
+                    }
+                }
+            } else {
+                if let Ok(value) = entity.get(sender, &pattern.1).await {
+                    ret.push((EventKey(name, pattern.1), value));
+                }
+            }
+        }
+        ret
+    }
 }
 
 #[async_trait]
@@ -67,12 +241,12 @@ impl Entity for Registry {
         &self,
         _sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
-    ) -> Result<Option<Vec<u8>>> {
+    ) -> BusResult<Option<Vec<u8>>> {
         let guard = self.entities.lock().await;
         if guard.forward.contains_key(key) {
-            Ok(None)
+            Ok(guard.descriptors.get(key).map(|(raw, _)| raw.to_owned()))
         } else {
-            strerr("not found")
+            Err(BusError::NotFound("not found".to_owned()))
         }
     }
     async fn set(
@@ -80,30 +254,40 @@ impl Entity for Registry {
         sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
         val: Vec<u8>,
-    ) -> Result<()> {
+        ttl: Option<Duration>,
+    ) -> BusResult<()> {
+        let descriptor = Descriptor::decode(&val).await.ok();
         let mut guard = self.entities.lock().await;
-        if guard.forward.contains_key(key) {
-            strerr("duplicated")
-        } else if let Some(sender) = sender {
-            if let Some(_) = guard.reverse.get(sender) {
-                strerr("too many names")
+        if ttl.is_some() {
+            Err(BusError::NotSupported("not supported".to_owned()))
+        } else if key.contains('*') {
+            Err(BusError::Malformed("invalid name".to_owned()))
+        } else if guard.forward.contains_key(key) {
+            Err(BusError::Duplicate("duplicated".to_owned()))
+        } else if let Some(sender) = sender.filter(|x| x.can_own_names()) {
+            guard.forward.insert(key.to_owned(), sender.to_owned());
+            if val.is_empty() {
+                guard.descriptors.remove(key);
             } else {
-                guard.forward.insert(key.to_owned(), sender.to_owned());
-                guard.reverse.insert(sender.to_owned(), key.to_owned());
-                sender.update_name(Some(key)).await;
-                get_event_broker()
-                    .send(
-                        EventKey(ShortText::build(b"registry"), key.to_owned()),
-                        Some(&val),
-                    )
-                    .await;
-                Ok(())
+                guard
+                    .descriptors
+                    .insert(key.to_owned(), (val.to_owned(), descriptor));
             }
+            let names = guard.reverse.entry(sender.to_owned()).or_insert_with(Vec::new);
+            names.push(key.to_owned());
+            sender.update_name(names).await;
+            get_event_broker()
+                .send(
+                    EventKey(ShortText::build(b"registry"), key.to_owned()),
+                    Some(&val),
+                )
+                .await;
+            Ok(())
         } else {
-            strerr("not supported")
+            Err(BusError::NotSupported("not supported".to_owned()))
         }
     }
-    async fn del(&self, sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> Result<()> {
+    async fn del(&self, sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> BusResult<()> {
         let mut guard = self.entities.lock().await;
         if let Some(sender) = sender {
             if let Some(target) = guard.forward.get(key) {
@@ -115,19 +299,31 @@ impl Entity for Registry {
                         )
                         .await;
                     guard.forward.remove(key);
-                    guard.reverse.remove(sender);
+                    guard.descriptors.remove(key);
+                    let names = guard.reverse.get_mut(sender).map(|names| {
+                        names.retain(|name| name != key);
+                        names.to_owned()
+                    });
+                    let names = names.unwrap_or_default();
+                    if names.is_empty() {
+                        guard.reverse.remove(sender);
+                    }
+                    sender.update_name(&names).await;
                     Ok(())
                 } else {
-                    strerr("not allowned")
+                    Err(BusError::Forbidden("not allowed".to_owned()))
                 }
             } else {
-                strerr("not found")
+                Err(BusError::NotFound("not found".to_owned()))
             }
         } else {
-            strerr("not supported")
+            Err(BusError::NotSupported("not supported".to_owned()))
         }
     }
-    async fn keys(&self, sender: Option<&Arc<dyn Entity>>) -> Result<Vec<(ShortText, AccessTag)>> {
+    async fn keys(
+        &self,
+        sender: Option<&Arc<dyn Entity>>,
+    ) -> BusResult<Vec<(ShortText, AccessTag)>> {
         let guard = self.entities.lock().await;
         let ret = guard
             .forward
@@ -148,3 +344,236 @@ impl Entity for Registry {
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::{EntityReceiver, ExternalEntity};
+    use crate::handshake::Capabilities;
+    use crate::packet::ResponsePayload;
+
+    fn client() -> Arc<ExternalEntity<Vec<u8>>> {
+        Arc::new(ExternalEntity::in_memory(Capabilities::NONE, None))
+    }
+
+    #[async_std::test]
+    async fn expire_reaches_group_members() {
+        let registry = Registry {
+            entities: Mutex::new(BidiMap::new()),
+            policy: GroupPolicy::RoundRobin,
+        };
+        let member = client();
+        let caller = client() as Arc<dyn EntityReceiver>;
+        let name = ShortText::build(b"workers");
+        registry
+            .join(&(Arc::clone(&member) as Arc<dyn Entity>), &name)
+            .await
+            .unwrap();
+        let group = registry.find(&name).await.unwrap();
+        let method = ShortText::build(b"m");
+        let timeout = Duration::from_millis(0);
+        group.call(&caller, 1, &method, b"", timeout).await.unwrap();
+        assert_eq!(member.pending_calls().await, 1);
+        registry.expire_all().await;
+        for _ in 0..100 {
+            if member.pending_calls().await == 0 {
+                break;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(member.pending_calls().await, 0);
+    }
+
+    struct Recorder(async_std::sync::Sender<ResponsePayload>);
+
+    #[async_trait]
+    impl EntityReceiver for Recorder {
+        async fn call_next(&self, _reqid: u32, _data: Vec<u8>) {}
+        async fn call_resp(&self, _reqid: u32, val: ResponsePayload) {
+            self.0.send(val).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn expire_reaches_unregistered_callee() {
+        let registry = Registry {
+            entities: Mutex::new(BidiMap::new()),
+            policy: GroupPolicy::RoundRobin,
+        };
+        let name = ShortText::build(b"svc");
+        let callee = client() as Arc<dyn Entity>;
+        let (s, r) = async_std::sync::channel(1);
+        let caller = Arc::new(Recorder(s)) as Arc<dyn EntityReceiver>;
+        registry
+            .entities
+            .lock()
+            .await
+            .forward
+            .insert(name, Arc::clone(&callee));
+        let method = ShortText::build(b"m");
+        let timeout = Duration::from_millis(0);
+        let ret = callee.call(&caller, 1, &method, b"", timeout).await;
+        assert_eq!(ret, Ok(()));
+        registry.track_callee(&callee).await;
+        registry.entities.lock().await.forward.remove(&name);
+        registry.expire_all().await;
+        let ret = async_std::io::timeout(Duration::from_secs(1), async { Ok(r.recv().await) });
+        assert!(matches!(
+            ret.await.unwrap(),
+            Ok(ResponsePayload::Failed(BusError::Timeout(_)))
+        ));
+    }
+
+    struct Stuck;
+
+    #[async_trait]
+    impl EntityReceiver for Stuck {
+        async fn call_resp(&self, _reqid: u32, _val: ResponsePayload) {
+            async_std::future::pending::<()>().await;
+        }
+    }
+
+    #[async_std::test]
+    async fn stuck_caller_does_not_block_expiry() {
+        let registry = Registry {
+            entities: Mutex::new(BidiMap::new()),
+            policy: GroupPolicy::RoundRobin,
+        };
+        let stuck = Arc::new(Stuck) as Arc<dyn EntityReceiver>;
+        let (s, r) = async_std::sync::channel(1);
+        let caller = Arc::new(Recorder(s)) as Arc<dyn EntityReceiver>;
+        let method = ShortText::build(b"m");
+        let timeout = Duration::from_millis(0);
+        let callers = [stuck, caller];
+        let callees = [client() as Arc<dyn Entity>, client()];
+        for (caller, callee) in callers.iter().zip(&callees) {
+            let ret = callee.call(caller, 1, &method, b"", timeout).await;
+            assert_eq!(ret, Ok(()));
+            registry.track_callee(callee).await;
+        }
+        let ret = async_std::io::timeout(Duration::from_secs(1), async {
+            registry.expire_all().await;
+            Ok(r.recv().await)
+        });
+        assert!(matches!(
+            ret.await.unwrap(),
+            Ok(ResponsePayload::Failed(BusError::Timeout(_)))
+        ));
+    }
+
+    #[async_std::test]
+    async fn delete_one_of_many_names() {
+        let registry = Registry {
+            entities: Mutex::new(BidiMap::new()),
+            policy: GroupPolicy::RoundRobin,
+        };
+        let owner = client();
+        let sender = Arc::clone(&owner) as Arc<dyn Entity>;
+        let other = client() as Arc<dyn Entity>;
+        let (a, b) = (ShortText::build(b"a"), ShortText::build(b"b"));
+        for name in &[a, b] {
+            let ret = registry.set(Some(&sender), name, Vec::new(), None).await;
+            assert_eq!(ret, Ok(()));
+        }
+        assert_eq!(owner.get_names().await, vec![a, b]);
+        assert!(matches!(
+            registry.del(Some(&other), &a).await,
+            Err(BusError::Forbidden(_))
+        ));
+        registry.del(Some(&sender), &a).await.unwrap();
+        assert_eq!(owner.get_names().await, vec![b]);
+        assert!(registry.find(&a).await.is_none());
+        assert!(registry.find(&b).await.is_some());
+        assert!(matches!(
+            registry.del(Some(&sender), &a).await,
+            Err(BusError::NotFound(_))
+        ));
+        registry.del(Some(&sender), &b).await.unwrap();
+        assert!(owner.get_names().await.is_empty());
+        assert!(registry.entities.lock().await.reverse.is_empty());
+    }
+
+    struct Transient;
+
+    #[async_trait]
+    impl Entity for Transient {
+        async fn get(
+            &self,
+            _sender: Option<&Arc<dyn Entity>>,
+            _key: &ShortText,
+        ) -> BusResult<Option<Vec<u8>>> {
+            Ok(None)
+        }
+        async fn set(
+            &self,
+            _sender: Option<&Arc<dyn Entity>>,
+            _key: &ShortText,
+            _val: Vec<u8>,
+            _ttl: Option<Duration>,
+        ) -> BusResult<()> {
+            Ok(())
+        }
+        async fn del(&self, _sender: Option<&Arc<dyn Entity>>, _key: &ShortText) -> BusResult<()> {
+            Ok(())
+        }
+        async fn keys(
+            &self,
+            _sender: Option<&Arc<dyn Entity>>,
+        ) -> BusResult<Vec<(ShortText, AccessTag)>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[async_std::test]
+    async fn only_connections_own_names() {
+        let registry = Registry {
+            entities: Mutex::new(BidiMap::new()),
+            policy: GroupPolicy::RoundRobin,
+        };
+        let peer = Arc::new(Transient) as Arc<dyn Entity>;
+        let name = ShortText::build(b"svc");
+        let ret = registry.set(Some(&peer), &name, Vec::new(), None).await;
+        assert!(matches!(ret, Err(BusError::NotSupported(_))));
+        assert!(matches!(
+            registry.join(&peer, &name).await,
+            Err(BusError::NotSupported(_))
+        ));
+        assert!(registry.find(&name).await.is_none());
+        let sender = client() as Arc<dyn Entity>;
+        let ret = registry.set(Some(&sender), &name, Vec::new(), None).await;
+        assert_eq!(ret, Ok(()));
+    }
+
+    #[async_std::test]
+    async fn snapshot_skips_unreadable_keys() {
+        let registry = Registry {
+            entities: Mutex::new(BidiMap::new()),
+            policy: GroupPolicy::RoundRobin,
+        };
+        let owner = client();
+        let other = client() as Arc<dyn Entity>;
+        let name = ShortText::build(b"svc");
+        let secret = ShortText::build(b"secret");
+        owner
+            .set_acl(&secret, AccessTag::Private, None)
+            .await
+            .unwrap();
+        let entity = Arc::clone(&owner) as Arc<dyn Entity>;
+        registry.entities.lock().await.forward.insert(name, entity);
+        let pattern = EventKey(name, secret);
+        let ret = registry.snapshot(Some(&other), &pattern).await;
+        assert!(ret.is_empty());
+        let missing = ShortText::build(b"missing");
+        let pattern = EventKey(name, missing);
+        let ret = registry.snapshot(Some(&other), &pattern).await;
+        assert!(ret.is_empty());
+        let empty = ShortText::build(b"empty");
+        owner
+            .set_acl(&empty, AccessTag::Public, None)
+            .await
+            .unwrap();
+        let pattern = EventKey(name, empty);
+        let ret = registry.snapshot(Some(&other), &pattern).await;
+        assert!(matches!(&ret[..], [(ek, None)] if ek.1 == empty));
+    }
+}
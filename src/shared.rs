@@ -1,32 +1,148 @@
 use crate::broker::{get_event_broker, EventKey};
-use crate::entity::{AccessTag, Entity};
+use crate::entity::{add_integer, AccessTag, CasResult, Condition, Entity};
+use crate::error::BusResult;
+use crate::persist::{get_shared_journal, Record};
 use crate::registry::StaticRegistryItem;
 use crate::short_text::ShortText;
+use crate::utils::{deadline_after, is_expired};
 use async_std::sync::{Arc, Mutex};
 use async_trait::async_trait;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+struct StoredValue {
+    data: Vec<u8>,
+    deadline: Option<Instant>,
+    version: u64,
+}
 
 pub struct SharedStorage {
-    data: Mutex<HashMap<ShortText, Vec<u8>>>,
+    data: Mutex<HashMap<ShortText, StoredValue>>,
+    revision: AtomicU64,
 }
 
 inventory::submit! {
     StaticRegistryItem(b"shared", Arc::new(SharedStorage{
-        data: Mutex::new(HashMap::new())
+        data: Mutex::new(HashMap::new()),
+        revision: AtomicU64::new(0),
     }))
 }
 
-impl SharedStorage {}
+impl SharedStorage {
+    async fn journal(&self, guard: &HashMap<ShortText, StoredValue>, record: Record) {
+        let journal = match get_shared_journal() {
+            Some(journal) => journal,
+            None => return,
+        };
+        if let Err(e) = journal.append(&record).await {
+            log::error!("failed to append journal: {}", e);
+        }
+        if journal.snapshot_due().await {
+            let revision = Record::Revision(self.revision.load(Ordering::Relaxed));
+            let records = guard.iter().map(|(key, x)| {
+                let data = x.data.clone();
+                Record::Set(key.to_owned(), data, x.deadline, x.version)
+            });
+            let records = std::iter::once(revision).chain(records);
+            if let Err(e) = journal.snapshot(records).await {
+                log::error!("failed to write snapshot: {}", e);
+            }
+        }
+    }
+
+    async fn store(
+        &self,
+        guard: &mut HashMap<ShortText, StoredValue>,
+        key: &ShortText,
+        val: Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> u64 {
+        get_event_broker()
+            .send(
+                EventKey(ShortText::build(b"shared"), key.to_owned()),
+                Some(&val[..]),
+            )
+            .await;
+        let version = self.revision.fetch_add(1, Ordering::Relaxed) + 1;
+        let record = Record::Set(key.to_owned(), val.clone(), deadline, version);
+        guard.insert(
+            key.to_owned(),
+            StoredValue {
+                data: val,
+                deadline,
+                version,
+            },
+        );
+        self.journal(guard, record).await;
+        version
+    }
+
+    async fn replay(&self, records: Vec<Record>) {
+        let mut guard = self.data.lock().await;
+        for record in records {
+            match record {
+                Record::Set(key, data, deadline, version) => {
+                    self.revision.fetch_max(version, Ordering::Relaxed);
+                    guard.insert(
+                        key,
+                        StoredValue {
+                            data,
+                            deadline,
+                            version,
+                        },
+                    );
+                }
+                Record::Del(key) => {
+                    guard.remove(&key);
+                }
+                Record::Revision(version) => {
+                    self.revision.fetch_max(version, Ordering::Relaxed);
+                }
+            }
+        }
+        let now = Instant::now();
+        guard.retain(|_, value| !is_expired(value.deadline, now));
+    }
+}
+
+fn current(value: Option<&StoredValue>) -> Option<&StoredValue> {
+    value.filter(|x| !is_expired(x.deadline, Instant::now()))
+}
 
 #[async_trait]
 impl Entity for SharedStorage {
+    async fn restore(&self) -> BusResult<()> {
+        let journal = match get_shared_journal() {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let records = journal.load().await?;
+        self.replay(records).await;
+        Ok(())
+    }
+    async fn expire(&self, now: Instant) {
+        let mut guard = self.data.lock().await;
+        let expired: Vec<_> = guard
+            .iter()
+            .filter(|(_, value)| is_expired(value.deadline, now))
+            .map(|(key, _)| key.to_owned())
+            .collect();
+        for key in expired {
+            get_event_broker()
+                .send(EventKey(ShortText::build(b"shared"), key.to_owned()), None)
+                .await;
+            guard.remove(&key);
+            self.journal(&guard, Record::Del(key)).await;
+        }
+    }
     async fn get(
         &self,
         _sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
-    ) -> std::io::Result<Option<Vec<u8>>> {
+    ) -> BusResult<Option<Vec<u8>>> {
         let guard = self.data.lock().await;
-        let ret = guard.get(key).map(|x| x.clone());
+        let ret = current(guard.get(key)).map(|x| x.data.clone());
         Ok(ret)
     }
     async fn set(
@@ -34,34 +150,117 @@ impl Entity for SharedStorage {
         _sender: Option<&Arc<dyn Entity>>,
         key: &ShortText,
         val: Vec<u8>,
-    ) -> std::io::Result<()> {
+        ttl: Option<Duration>,
+    ) -> BusResult<()> {
+        let deadline = deadline_after(ttl)?;
         let mut guard = self.data.lock().await;
-        get_event_broker()
-            .send(
-                EventKey(ShortText::build(b"shared"), key.to_owned()),
-                Some(&val[..]),
-            )
-            .await;
-        guard.insert(key.to_owned(), val);
+        self.store(&mut guard, key, val, deadline).await;
         Ok(())
     }
-    async fn del(&self, _sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> std::io::Result<()> {
+    async fn del(&self, _sender: Option<&Arc<dyn Entity>>, key: &ShortText) -> BusResult<()> {
         let mut guard = self.data.lock().await;
         get_event_broker()
             .send(EventKey(ShortText::build(b"shared"), key.to_owned()), None)
             .await;
         guard.remove(key);
-        todo!()
+        self.journal(&guard, Record::Del(key.to_owned())).await;
+        Ok(())
     }
     async fn keys(
         &self,
         _sender: Option<&Arc<dyn Entity>>,
-    ) -> std::io::Result<Vec<(ShortText, AccessTag)>> {
+    ) -> BusResult<Vec<(ShortText, AccessTag)>> {
         let guard = self.data.lock().await;
+        let now = Instant::now();
         let ret = guard
-            .keys()
-            .map(|x| (x.to_owned(), AccessTag::Public))
+            .iter()
+            .filter(|(_, value)| !is_expired(value.deadline, now))
+            .map(|(key, _)| (key.to_owned(), AccessTag::Public))
             .collect();
         Ok(ret)
     }
+    async fn get_versioned(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        key: &ShortText,
+    ) -> BusResult<(u64, Option<Vec<u8>>)> {
+        let guard = self.data.lock().await;
+        let ret = current(guard.get(key)).map_or((0, None), |x| (x.version, Some(x.data.clone())));
+        Ok(ret)
+    }
+    async fn compare_and_set(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        key: &ShortText,
+        cond: Condition,
+        val: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BusResult<CasResult> {
+        let deadline = deadline_after(ttl)?;
+        let mut guard = self.data.lock().await;
+        let stored = current(guard.get(key));
+        let version = stored.map_or(0, |x| x.version);
+        if !cond.check(version, stored.map(|x| &x.data)) {
+            return Ok(CasResult::Mismatch(version));
+        }
+        let version = self.store(&mut guard, key, val, deadline).await;
+        Ok(CasResult::Applied(version))
+    }
+    async fn increment(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        key: &ShortText,
+        delta: i64,
+    ) -> BusResult<i64> {
+        let mut guard = self.data.lock().await;
+        let stored = current(guard.get(key));
+        let ret = add_integer(stored.map(|x| &x.data), delta)?;
+        let deadline = stored.and_then(|x| x.deadline);
+        self.store(&mut guard, key, ret.to_string().into_bytes(), deadline)
+            .await;
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> SharedStorage {
+        SharedStorage {
+            data: Mutex::new(HashMap::new()),
+            revision: AtomicU64::new(0),
+        }
+    }
+
+    #[async_std::test]
+    async fn replay_keeps_versions() {
+        let storage = storage();
+        let (a, b) = (ShortText::build(b"a"), ShortText::build(b"b"));
+        let records = vec![
+            Record::Revision(500),
+            Record::Set(a, b"1".to_vec(), None, 120),
+            Record::Set(b, b"2".to_vec(), None, 480),
+            Record::Del(b),
+        ];
+        storage.replay(records).await;
+        let ret = storage.get_versioned(None, &a).await;
+        assert_eq!(ret, Ok((120, Some(b"1".to_vec()))));
+        assert_eq!(storage.get_versioned(None, &b).await, Ok((0, None)));
+        let cond = Condition::Version(0);
+        let value = b"3".to_vec();
+        let ret = storage.compare_and_set(None, &b, cond, value, None).await;
+        assert!(matches!(ret, Ok(CasResult::Applied(501))));
+    }
+
+    #[async_std::test]
+    async fn replay_takes_highest_version() {
+        let storage = storage();
+        let a = ShortText::build(b"a");
+        let records = vec![Record::Revision(7), Record::Set(a, b"1".to_vec(), None, 9)];
+        storage.replay(records).await;
+        storage.set(None, &a, b"2".to_vec(), None).await.unwrap();
+        let ret = storage.get_versioned(None, &a).await;
+        assert_eq!(ret, Ok((10, Some(b"2".to_vec()))));
+    }
 }
@@ -0,0 +1,77 @@
+use crate::utils::strerr;
+use async_std::fs;
+use async_std::io::{self, Result};
+use async_std::net::TcpStream;
+use async_std::path::Path;
+use futures_rustls::rustls::internal::pemfile;
+use futures_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, ServerSession,
+    Session,
+};
+use futures_rustls::TlsAcceptor;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
+use x509_parser::parse_x509_certificate;
+
+// a peer that never finishes the handshake must not hold the connection forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type TlsStream = async_dup::Arc<async_dup::Mutex<futures_rustls::server::TlsStream<TcpStream>>>;
+
+async fn read_pem(path: &Path) -> Result<BufReader<std::io::Cursor<Vec<u8>>>> {
+    Ok(BufReader::new(std::io::Cursor::new(fs::read(path).await?)))
+}
+
+pub async fn load_acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let certs = match pemfile::certs(&mut read_pem(cert).await?) {
+        Ok(certs) if !certs.is_empty() => certs,
+        _ => return strerr(format!("no certificate found in {}", cert.display())),
+    };
+    let mut keys = pemfile::pkcs8_private_keys(&mut read_pem(key).await?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut read_pem(key).await?).unwrap_or_default();
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return strerr(format!("no private key found in {}", key.display())),
+    };
+    let verifier = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut read_pem(path).await?) {
+                Ok((added, _)) if added > 0 => AllowAnyAuthenticatedClient::new(roots),
+                _ => return strerr(format!("no CA certificate found in {}", path.display())),
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    if let Err(e) = config.set_single_cert(certs, key) {
+        return strerr(e.to_string());
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<(TlsStream, Option<String>)> {
+    let stream = io::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await?;
+    let identity = peer_identity(stream.get_ref().1);
+    Ok((async_dup::Arc::new(async_dup::Mutex::new(stream)), identity))
+}
+
+fn peer_identity(session: &ServerSession) -> Option<String> {
+    let certs = session.get_peer_certificates()?;
+    let (_, cert) = parse_x509_certificate(&certs.first()?.0).ok()?;
+    let subject = cert.subject();
+    let common_name = subject
+        .iter_common_name()
+        .next()
+        .and_then(|x| x.as_str().ok())
+        .map(|x| x.to_owned());
+    let identity = common_name.unwrap_or_else(|| subject.to_string());
+    if identity.is_empty() || identity.len() > 255 {
+        None
+    } else {
+        Some(identity)
+    }
+}
@@ -0,0 +1,128 @@
+use crate::gateway::handle_client;
+use crate::utils::strerr;
+use async_std::fs;
+use async_std::io::{ErrorKind, Result};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::path::{Path, PathBuf};
+use async_std::prelude::*;
+use async_std::task;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+pub fn parse_mode(text: &str) -> std::result::Result<u32, String> {
+    u32::from_str_radix(text, 8).map_err(|_| format!("invalid file mode: {}", text))
+}
+
+pub fn parse_owner(text: &str) -> std::result::Result<(u32, Option<u32>), String> {
+    let mut parts = text.splitn(2, ':');
+    let uid = parts.next().and_then(|x| x.parse().ok());
+    let gid = parts.next().map(|x| x.parse().ok());
+    match (uid, gid) {
+        (Some(uid), None) => Ok((uid, None)),
+        (Some(uid), Some(Some(gid))) => Ok((uid, Some(gid))),
+        _ => Err(format!("invalid owner: {}", text)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_identity(stream: &UnixStream) -> Option<String> {
+    use std::os::unix::io::AsRawFd;
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Some(format!("unix:{}:{}", cred.uid, cred.gid))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_identity(_stream: &UnixStream) -> Option<String> {
+    None
+}
+
+// binds inside a private directory and moves the socket into place once mode and owner apply,
+// so nobody can connect through the default permissions in between
+async fn bind(
+    path: &Path,
+    staging: &Path,
+    mode: Option<u32>,
+    owner: Option<(u32, Option<u32>)>,
+) -> Result<UnixListener> {
+    std::fs::DirBuilder::new().mode(0o700).create(staging)?;
+    let temp = staging.join("socket");
+    let listener = UnixListener::bind(&temp).await?;
+    if let Some(mode) = mode {
+        fs::set_permissions(&temp, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    if let Some((uid, gid)) = owner {
+        std::os::unix::fs::chown(&temp, Some(uid), gid)?;
+    }
+    fs::rename(&temp, path).await?;
+    Ok(listener)
+}
+
+pub async fn serve(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<(u32, Option<u32>)>,
+) -> Result<()> {
+    match fs::metadata(path).await {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path).await?,
+        Ok(_) => return strerr(format!("{} exists and is not a socket", path.display())),
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(".{}.tmp", std::process::id()));
+    let staging = PathBuf::from(staging);
+    let listener = bind(path, &staging, mode, owner).await;
+    let _ = fs::remove_dir_all(&staging).await;
+    let listener = listener?;
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        let peer = peer_identity(&stream);
+        task::spawn(handle_client(stream, peer));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("rw").is_err());
+        assert_eq!(parse_owner("1000"), Ok((1000, None)));
+        assert_eq!(parse_owner("1000:100"), Ok((1000, Some(100))));
+        assert!(parse_owner("1000:").is_err());
+        assert!(parse_owner("root").is_err());
+    }
+
+    #[async_std::test]
+    async fn bind_applies_mode_before_exposing() {
+        let name = format!("minibus-unix-{}", std::process::id());
+        let path = PathBuf::from(std::env::temp_dir()).join(name);
+        let staging = path.with_extension("tmp");
+        let listener = bind(&path, &staging, Some(0o600), None).await.unwrap();
+        let meta = fs::metadata(&path).await.unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.mode() & 0o777, 0o600);
+        fs::remove_dir_all(&staging).await.unwrap();
+        UnixStream::connect(&path).await.unwrap();
+        drop(listener);
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -1,5 +1,7 @@
+use crate::error::{BusError, BusResult};
+use crate::limits::get_limits;
 use crate::short_text::ShortText;
-use async_std::io::{BufRead, Read, ReadExt, Result, Write};
+use async_std::io::{Error, ErrorKind, Read, ReadExt, Result, Write};
 use async_std::prelude::*;
 use async_trait::async_trait;
 use rand::distributions::{Distribution, Standard};
@@ -8,12 +10,7 @@ use std::borrow::Borrow;
 use std::collections::{BTreeMap, HashMap};
 use std::hash::{BuildHasher, Hash};
 use std::mem::transmute;
-
-pub async fn verify_client<T: BufRead + Unpin>(reader: &mut T) -> Result<bool> {
-    let mut buf = [0u8; 8];
-    reader.read_exact(&mut buf).await?;
-    Ok(&buf == b"MINIBUS\x00")
-}
+use std::time::{Duration, Instant};
 
 #[async_trait]
 pub trait EncoderUtils {
@@ -87,21 +84,27 @@ impl<T: Read + Unpin + Send> DecoderUtils for T {
     }
     async fn decode_varuint(&mut self) -> Result<usize> {
         let mut buf = [0u8; 1];
-        self.read_exact(&mut buf).await?;
-        let ret = buf[0] as usize;
-        if ret < 128 {
-            return Ok(ret);
+        let mut ret = 0usize;
+        let mut shift = 0;
+        loop {
+            self.read_exact(&mut buf).await?;
+            let part = (buf[0] & 0b01111111) as usize;
+            if shift >= usize::MAX.count_ones() || (part << shift) >> shift != part {
+                return Err(Error::new(ErrorKind::InvalidData, "varuint overflow"));
+            }
+            ret |= part << shift;
+            if buf[0] < 128 {
+                return Ok(ret);
+            }
+            shift += 7;
         }
-        let ret = ret & 0b01111111;
-        let ret = ret + (self.decode_varuint().await? << 7);
-        Ok(ret)
     }
     async fn decode_binary(&mut self) -> Result<Vec<u8>> {
         let len = self.decode_varuint().await?;
-        let mut buf: Vec<u8> = Vec::with_capacity(len);
-        unsafe {
-            buf.set_len(len);
+        if len > get_limits().max_payload {
+            return Err(Error::new(ErrorKind::InvalidData, "payload too large"));
         }
+        let mut buf = vec![0u8; len];
         self.read_exact(&mut buf).await?;
         Ok(buf)
     }
@@ -163,3 +166,78 @@ where
 pub fn strerr<T, S: Into<String>>(data: S) -> Result<T> {
     Err(std::io::Error::new(std::io::ErrorKind::Other, data.into()))
 }
+
+pub fn deadline_after(ttl: Option<Duration>) -> BusResult<Option<Instant>> {
+    match ttl {
+        Some(ttl) => match Instant::now().checked_add(ttl) {
+            Some(deadline) => Ok(Some(deadline)),
+            None => Err(BusError::Malformed("ttl out of range".to_owned())),
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn is_expired(deadline: Option<Instant>, now: Instant) -> bool {
+    matches!(deadline, Some(deadline) if deadline <= now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn varuint_round_trip() {
+        for value in &[0, 1, 127, 128, 300, usize::MAX] {
+            let mut buf = Vec::new();
+            buf.encode_varuint(*value).await.unwrap();
+            let mut payload = buf.as_slice();
+            assert_eq!(payload.decode_varuint().await.unwrap(), *value);
+            assert!(payload.is_empty());
+        }
+    }
+
+    #[async_std::test]
+    async fn varuint_overflow() {
+        let mut payload: &[u8] = &[0xff; 16];
+        let e = payload.decode_varuint().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let mut buf = vec![0xff; 9];
+        buf.push(0x02);
+        let e = buf.as_slice().decode_varuint().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[async_std::test]
+    async fn binary_payload_cap() {
+        let len = get_limits().max_payload + 1;
+        let mut buf = Vec::new();
+        buf.encode_varuint(len).await.unwrap();
+        let e = buf.as_slice().decode_binary().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let mut buf = Vec::new();
+        buf.encode_binary(b"abc").await.unwrap();
+        assert_eq!(buf.as_slice().decode_binary().await.unwrap(), b"abc");
+    }
+
+    #[test]
+    fn deadline_after_ttl() {
+        assert_eq!(deadline_after(None), Ok(None));
+        let now = Instant::now();
+        let deadline = deadline_after(Some(Duration::from_secs(1)))
+            .unwrap()
+            .unwrap();
+        assert!(deadline > now);
+        assert!(!is_expired(Some(deadline), now));
+        assert!(is_expired(Some(deadline), deadline));
+        assert!(!is_expired(None, deadline));
+    }
+
+    #[test]
+    fn deadline_after_overflow() {
+        let ttl = Duration::new(u64::MAX, 0);
+        assert!(matches!(
+            deadline_after(Some(ttl)),
+            Err(BusError::Malformed(_))
+        ));
+    }
+}
@@ -1,46 +1,223 @@
 use crate::broker::*;
+use crate::descriptor::{self, Descriptor};
 use crate::entity::*;
+use crate::error::{BusError, BusResult};
+use crate::limits::get_limits;
 use crate::packet::ResponsePayload;
+use crate::policy::{self, Permission};
 use crate::registry::Registry;
-use async_std::sync::{channel, Arc, Receiver, Sender};
+use crate::short_text::ShortText;
+use crate::tls;
+use crate::websocket;
+use async_std::future;
+use async_std::io;
+use async_std::net::TcpListener;
+use async_std::prelude::*;
+use async_std::stream::Stream;
+use async_std::sync::{channel, Arc, Mutex, Receiver, Sender};
+use async_std::task;
 use async_trait::async_trait;
 use base64;
+use futures_rustls::TlsAcceptor;
+use serde_json::json;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tide::Endpoint;
 
-struct WebReceiver(tide::sse::Sender);
+struct WebStream {
+    sender: tide::sse::Sender,
+    backlog: Mutex<Option<Snapshot>>,
+}
 
-#[async_trait]
-impl AlternativeReceiver for WebReceiver {
-    async fn receive(&self, data: Option<&[u8]>) -> bool {
+impl WebStream {
+    fn new(sender: tide::sse::Sender, retain: bool) -> WebStream {
+        WebStream {
+            sender,
+            backlog: Mutex::new(if retain { Some(Vec::new()) } else { None }),
+        }
+    }
+
+    async fn send(&self, key: &EventKey, data: Option<&[u8]>) -> bool {
+        let id = format!("{}/{}", key.0, key.1);
         let res = if let Some(data) = data {
             if let Ok(data) = std::str::from_utf8(data) {
-                self.0.send("text", data, None).await
+                self.sender.send("text", data, Some(&id)).await
             } else {
                 let data = base64::encode(data);
-                self.0.send("base64", data, None).await
+                self.sender.send("base64", data, Some(&id)).await
             }
         } else {
-            self.0.send("null", "", None).await
+            self.sender.send("null", "", Some(&id)).await
         };
         res.is_ok()
     }
+
+    async fn release(&self, snapshot: Snapshot) -> bool {
+        let mut guard = self.backlog.lock().await;
+        let pending = guard.take().unwrap_or_default();
+        for (key, data) in snapshot.into_iter().chain(pending) {
+            if !self.send(&key, data.as_deref()).await {
+                return false;
+            }
+        }
+        true
+    }
 }
 
-struct CallReceiver(Sender<ResponsePayload>);
+struct WebReceiver(Arc<WebStream>);
+
+#[async_trait]
+impl AlternativeReceiver for WebReceiver {
+    async fn receive(&self, key: &EventKey, data: Option<&[u8]>) -> bool {
+        let mut guard = self.0.backlog.lock().await;
+        if let Some(list) = guard.as_mut() {
+            list.push((key.to_owned(), data.map(|x| x.to_vec())));
+            return true;
+        }
+        self.0.send(key, data).await
+    }
+}
+
+enum CallEvent {
+    Next(Vec<u8>),
+    Done(ResponsePayload),
+}
+
+// how long the callee may wait on a client that stopped reading before the stream is dropped
+const CALL_SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct CallReceiver(Mutex<Option<Sender<CallEvent>>>);
 
 impl CallReceiver {
-    fn new() -> (CallReceiver, Receiver<ResponsePayload>) {
+    fn new() -> (CallReceiver, Receiver<CallEvent>) {
         let (s, r) = channel(1);
-        (CallReceiver(s), r)
+        (CallReceiver(Mutex::new(Some(s))), r)
+    }
+
+    async fn deliver(&self, event: CallEvent) {
+        let mut guard = self.0.lock().await;
+        if let Some(sender) = guard.as_ref() {
+            if future::timeout(CALL_SEND_TIMEOUT, sender.send(event))
+                .await
+                .is_err()
+            {
+                log::warn!("dropping a call stream that is not being read");
+                guard.take();
+            }
+        }
     }
 }
 
 #[async_trait]
 impl EntityReceiver for CallReceiver {
+    async fn call_next(&self, _reqid: u32, data: Vec<u8>) {
+        self.deliver(CallEvent::Next(data)).await;
+    }
     async fn call_resp(&self, _reqid: u32, val: ResponsePayload) {
-        self.0.send(val).await;
+        self.deliver(CallEvent::Done(val)).await;
+    }
+}
+
+struct CallStream {
+    _receiver: Arc<dyn EntityReceiver>,
+    events: Receiver<CallEvent>,
+    chunk: Vec<u8>,
+    done: bool,
+}
+
+impl io::Read for CallStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if !this.chunk.is_empty() {
+                let len = buf.len().min(this.chunk.len());
+                buf[..len].copy_from_slice(&this.chunk[..len]);
+                this.chunk.drain(..len);
+                return Poll::Ready(Ok(len));
+            }
+            if this.done {
+                return Poll::Ready(Ok(0));
+            }
+            match Pin::new(&mut this.events).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    this.done = true;
+                    let e = BusError::Remote("call stream dropped".to_owned());
+                    return Poll::Ready(Err(e.into()));
+                }
+                Poll::Ready(Some(CallEvent::Next(data))) => this.chunk = data,
+                Poll::Ready(Some(CallEvent::Done(payload))) => {
+                    this.done = true;
+                    match payload {
+                        ResponsePayload::SuccessWithData(data) => this.chunk = data,
+                        ResponsePayload::Failed(e) => return Poll::Ready(Err(e.into())),
+                        _ => {}
+                    }
+                }
+            }
+        }
     }
 }
 
+struct PeerIdentity(String);
+
+fn peer_identity(req: &tide::Request<()>) -> Option<&str> {
+    req.ext::<PeerIdentity>().map(|x| x.0.as_str())
+}
+
+// stands in for the http client as the sender, so per-key allowlists see its identity
+struct WebPeer(String);
+
+#[async_trait]
+impl Entity for WebPeer {
+    fn identity(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+    async fn get(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        _key: &ShortText,
+    ) -> BusResult<Option<Vec<u8>>> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn set(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+        _key: &ShortText,
+        _val: Vec<u8>,
+        _ttl: Option<Duration>,
+    ) -> BusResult<()> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn del(&self, _sender: Option<&Arc<dyn Entity>>, _key: &ShortText) -> BusResult<()> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+    async fn keys(
+        &self,
+        _sender: Option<&Arc<dyn Entity>>,
+    ) -> BusResult<Vec<(ShortText, AccessTag)>> {
+        Err(BusError::NotSupported("not supported".to_owned()))
+    }
+}
+
+fn web_peer(req: &tide::Request<()>) -> Option<Arc<dyn Entity>> {
+    peer_identity(req).map(|x| Arc::new(WebPeer(x.to_owned())) as Arc<dyn Entity>)
+}
+
+fn authorize(
+    req: &tide::Request<()>,
+    entity: &ShortText,
+    key: &ShortText,
+    permission: Permission,
+) -> tide::Result<()> {
+    policy::check(peer_identity(req), permission, entity, key).map_err(http_error)
+}
+
 fn get_access_tag_flag(tag: AccessTag) -> char {
     match tag {
         AccessTag::Private => '!',
@@ -49,88 +226,347 @@ fn get_access_tag_flag(tag: AccessTag) -> char {
     }
 }
 
+fn get_access_tag_name(tag: AccessTag) -> &'static str {
+    match tag {
+        AccessTag::Private => "private",
+        AccessTag::Protected => "protected",
+        AccessTag::Public => "public",
+    }
+}
+
+fn wants_json(req: &tide::Request<()>) -> bool {
+    match req.header("Accept") {
+        Some(values) => values
+            .iter()
+            .any(|x| x.as_str().contains("application/json")),
+        None => false,
+    }
+}
+
+fn encode_value(data: &[u8]) -> serde_json::Value {
+    match std::str::from_utf8(data) {
+        Ok(text) => json!({"encoding": "utf8", "value": text}),
+        Err(_) => json!({"encoding": "base64", "value": base64::encode(data)}),
+    }
+}
+
+fn error_status(e: &BusError) -> tide::StatusCode {
+    match e {
+        BusError::NotFound(_) => tide::StatusCode::NotFound,
+        BusError::Forbidden(_) => tide::StatusCode::Forbidden,
+        BusError::Duplicate(_) => tide::StatusCode::Conflict,
+        BusError::NotSupported(_) => tide::StatusCode::MethodNotAllowed,
+        BusError::Timeout(_) => tide::StatusCode::GatewayTimeout,
+        BusError::Limit(_) => tide::StatusCode::TooManyRequests,
+        BusError::Malformed(_) => tide::StatusCode::BadRequest,
+        BusError::Remote(_) => tide::StatusCode::BadGateway,
+    }
+}
+
+fn http_error(e: BusError) -> tide::Error {
+    tide::Error::new(error_status(&e), e)
+}
+
+fn not_found(message: &str) -> tide::Error {
+    http_error(BusError::NotFound(message.to_owned()))
+}
+
+fn malformed(message: &str) -> tide::Error {
+    http_error(BusError::Malformed(message.to_owned()))
+}
+
+// errors reported by the callee are upstream failures, whatever their kind
+fn remote_error(e: BusError) -> tide::Error {
+    let status = match e {
+        BusError::Timeout(_) => tide::StatusCode::GatewayTimeout,
+        _ => tide::StatusCode::BadGateway,
+    };
+    tide::Error::new(status, e)
+}
+
+// puts the error message into the body: {"error": {"status", "code", "message"}} for json
+// clients, where "code" is the bus error code and only present when the error carries one
+struct ErrorBody;
+
+#[async_trait]
+impl tide::Middleware<()> for ErrorBody {
+    async fn handle(&self, req: tide::Request<()>, next: tide::Next<'_, ()>) -> tide::Result {
+        let json = wants_json(&req);
+        let mut res = next.run(req).await;
+        let status = res.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return Ok(res);
+        }
+        let code = res
+            .error()
+            .and_then(|e| e.downcast_ref::<BusError>())
+            .map(BusError::code);
+        let mut message = match res.take_error() {
+            Some(e) => e.to_string(),
+            None if json => res.take_body().into_string().await.unwrap_or_default(),
+            None => return Ok(res),
+        };
+        if !json {
+            res.set_body(message);
+            return Ok(res);
+        }
+        if message.is_empty() {
+            message = status.canonical_reason().to_owned();
+        }
+        let mut error = json!({"status": status as u16, "message": message});
+        if let Some(code) = code {
+            error["code"] = json!(code);
+        }
+        res.set_body(json!({ "error": error }));
+        Ok(res)
+    }
+}
+
 async fn get_bucket(req: tide::Request<()>) -> tide::Result<tide::Response> {
     let bucket = req.param("bucket")?;
+    authorize(&req, &bucket, &ShortText::build(b"*"), Permission::Get)?;
     if let Some(bucket) = Registry::get_global().find(&bucket).await {
-        let keys = bucket.keys(None).await?;
+        let peer = web_peer(&req);
+        let keys = bucket.keys(peer.as_ref()).await.map_err(http_error)?;
+        if wants_json(&req) {
+            let list: Vec<_> = keys
+                .iter()
+                .map(|(name, tag)| {
+                    json!({"name": name.to_string(), "access": get_access_tag_name(*tag)})
+                })
+                .collect();
+            return Ok(tide::Response::from(json!(list)));
+        }
         let vec: Vec<_> = keys
             .iter()
             .map(|(name, tag)| format!("{}{}", get_access_tag_flag(*tag), name))
             .collect();
         Ok(tide::Response::from(vec.join("\x00")))
     } else {
-        Ok(tide::Response::new(400))
+        Err(not_found("target not found"))
     }
 }
 
 async fn get_bucket_key(req: tide::Request<()>) -> tide::Result<tide::Response> {
     let bucket = req.param("bucket")?;
     let key = req.param("key")?;
+    authorize(&req, &bucket, &key, Permission::Get)?;
     if let Some(bucket) = Registry::get_global().find(&bucket).await {
-        if let Some(data) = bucket.get(None, &key).await? {
-            Ok(tide::Response::builder(400).body(data).build())
-        } else {
-            Ok(tide::Response::new(204))
+        let peer = web_peer(&req);
+        if let Ok((version, data)) = bucket.get_versioned(peer.as_ref(), &key).await {
+            return Ok(match data {
+                Some(data) if wants_json(&req) => {
+                    let mut value = encode_value(&data);
+                    value["version"] = json!(version);
+                    tide::Response::builder(200)
+                        .header("ETag", format!("\"{}\"", version))
+                        .body(value)
+                        .build()
+                }
+                Some(data) => tide::Response::builder(200)
+                    .header("ETag", format!("\"{}\"", version))
+                    .body(data)
+                    .build(),
+                None => return Err(not_found("no value")),
+            });
+        }
+        match bucket.get(peer.as_ref(), &key).await.map_err(http_error)? {
+            Some(data) if wants_json(&req) => Ok(tide::Response::from(encode_value(&data))),
+            Some(data) => Ok(tide::Response::builder(200).body(data).build()),
+            None => Err(not_found("no value")),
         }
     } else {
-        Ok(tide::Response::new(400))
+        Err(not_found("target not found"))
     }
 }
 
 async fn put_bucket_key(mut req: tide::Request<()>) -> tide::Result<tide::Response> {
     let bucket = req.param("bucket")?;
     let key = req.param("key")?;
+    authorize(&req, &bucket, &key, policy::write_permission(&bucket))?;
     if let Some(bucket) = Registry::get_global().find(&bucket).await {
+        let ttl = req
+            .url()
+            .query_pairs()
+            .find(|(name, _)| name == "ttl")
+            .and_then(|(_, value)| value.parse().ok())
+            .map(Duration::from_millis);
+        let cond = if let Some(version) = req.header("If-Match") {
+            match version.last().as_str().trim_matches('"').parse() {
+                Ok(version) => Some(Condition::Version(version)),
+                Err(_) => return Err(malformed("bad If-Match version")),
+            }
+        } else if let Some(tag) = req.header("If-None-Match") {
+            if tag.last().as_str() != "*" {
+                return Err(malformed("If-None-Match must be *"));
+            }
+            Some(Condition::Version(0))
+        } else {
+            None
+        };
+        let peer = web_peer(&req);
         let value = req.body_bytes().await?;
-        bucket.set(None, &key, value).await?;
-        Ok(tide::Response::new(204))
+        if let Some(cond) = cond {
+            match bucket
+                .compare_and_set(peer.as_ref(), &key, cond, value, ttl)
+                .await
+                .map_err(http_error)?
+            {
+                CasResult::Applied(version) => Ok(tide::Response::builder(204)
+                    .header("ETag", format!("\"{}\"", version))
+                    .build()),
+                CasResult::Mismatch(version) => Ok(tide::Response::builder(412)
+                    .header("ETag", format!("\"{}\"", version))
+                    .build()),
+            }
+        } else {
+            bucket
+                .set(peer.as_ref(), &key, value, ttl)
+                .await
+                .map_err(http_error)?;
+            Ok(tide::Response::new(204))
+        }
+    } else {
+        Err(not_found("target not found"))
+    }
+}
+
+async fn patch_bucket_key(mut req: tide::Request<()>) -> tide::Result<tide::Response> {
+    let bucket = req.param("bucket")?;
+    let key = req.param("key")?;
+    authorize(&req, &bucket, &key, policy::write_permission(&bucket))?;
+    if let Some(bucket) = Registry::get_global().find(&bucket).await {
+        let body = req.body_string().await?;
+        let delta = match body.trim() {
+            "" => 1,
+            delta => match delta.parse() {
+                Ok(delta) => delta,
+                Err(_) => return Err(malformed("not an integer")),
+            },
+        };
+        let value = bucket
+            .increment(web_peer(&req).as_ref(), &key, delta)
+            .await
+            .map_err(http_error)?;
+        if wants_json(&req) {
+            return Ok(tide::Response::from(json!({ "value": value })));
+        }
+        Ok(tide::Response::from(value.to_string()))
     } else {
-        Ok(tide::Response::new(400))
+        Err(not_found("target not found"))
     }
 }
 
 async fn delete_bucket_key(req: tide::Request<()>) -> tide::Result<tide::Response> {
     let bucket = req.param("bucket")?;
     let key = req.param("key")?;
+    authorize(&req, &bucket, &key, policy::write_permission(&bucket))?;
     if let Some(bucket) = Registry::get_global().find(&bucket).await {
-        bucket.del(None, &key).await?;
+        bucket
+            .del(web_peer(&req).as_ref(), &key)
+            .await
+            .map_err(http_error)?;
         Ok(tide::Response::new(204))
     } else {
-        Ok(tide::Response::new(400))
+        Err(not_found("target not found"))
     }
 }
 
 async fn post_bucket_key(mut req: tide::Request<()>) -> tide::Result<tide::Response> {
-    let bucket = req.param("bucket")?;
+    let name = req.param("bucket")?;
     let key = req.param("key")?;
-    if let Some(bucket) = Registry::get_global().find(&bucket).await {
+    authorize(&req, &name, &key, Permission::Call)?;
+    if let Some(bucket) = Registry::get_global().find(&name).await {
+        let timeout = req
+            .url()
+            .query_pairs()
+            .find(|(name, _)| name == "timeout")
+            .and_then(|(_, value)| value.parse().ok())
+            .map_or(get_limits().call_timeout, Duration::from_millis);
         let value = req.body_bytes().await?;
+        if let Err(e) = Registry::get_global().check_call(&name, &key, &value).await {
+            return Err(http_error(e));
+        }
+        let callee = match bucket.select_callee().await.map_err(http_error)? {
+            Some(member) => member,
+            None => bucket,
+        };
         let (s, r) = CallReceiver::new();
         let s = Arc::new(s) as Arc<dyn EntityReceiver>;
-        bucket.call(&s, 0, &key, &value[..]).await?;
+        callee
+            .call(&s, 0, &key, &value[..], timeout)
+            .await
+            .map_err(http_error)?;
+        Registry::get_global().track_callee(&callee).await;
         match r.recv().await {
-            Ok(ResponsePayload::Success) => Ok(tide::Response::new(204)),
-            Ok(ResponsePayload::SuccessWithData(data)) => {
-                Ok(tide::Response::builder(400).body(data).build())
+            Ok(CallEvent::Done(ResponsePayload::Success)) => Ok(tide::Response::new(204)),
+            Ok(CallEvent::Done(ResponsePayload::SuccessWithData(data))) if wants_json(&req) => {
+                Ok(tide::Response::from(encode_value(&data)))
             }
-            Ok(ResponsePayload::Failed(e)) => Ok(tide::Response::builder(400).body(e).build()),
-            Err(e) => Ok(tide::Response::builder(500).body(e.to_string()).build()),
+            Ok(CallEvent::Done(ResponsePayload::SuccessWithData(data))) => {
+                Ok(tide::Response::builder(200).body(data).build())
+            }
+            Ok(CallEvent::Done(ResponsePayload::Failed(e))) => Err(remote_error(e)),
+            Ok(CallEvent::Done(ResponsePayload::Mismatch(_))) => Ok(tide::Response::new(412)),
+            Ok(CallEvent::Next(chunk)) => {
+                let stream = CallStream {
+                    _receiver: s,
+                    events: r,
+                    chunk,
+                    done: false,
+                };
+                let body = tide::Body::from_reader(io::BufReader::new(stream), None);
+                Ok(tide::Response::builder(200).body(body).build())
+            }
+            Err(_) => Err(remote_error(BusError::Remote("call dropped".to_owned()))),
         }
     } else {
-        Ok(tide::Response::new(400))
+        Err(not_found("target not found"))
+    }
+}
+
+async fn get_registry_name(req: tide::Request<()>) -> tide::Result<tide::Response> {
+    let name = req.param("name")?;
+    authorize(&req, &ShortText::build(b"registry"), &name, Permission::Get)?;
+    match Registry::get_global().get(None, &name).await {
+        Ok(Some(data)) => match Descriptor::decode(&data).await {
+            Ok(descriptor) => Ok(tide::Response::from(descriptor.render(&name))),
+            Err(_) => Ok(tide::Response::builder(200).body(data).build()),
+        },
+        Ok(None) => Ok(tide::Response::new(204)),
+        Err(e) => Err(http_error(e)),
     }
 }
 
+async fn get_openapi(req: tide::Request<()>) -> tide::Result<tide::Response> {
+    let registry = ShortText::build(b"registry");
+    let mut services = Registry::get_global().descriptors().await;
+    services.retain(|(name, _)| {
+        policy::check(peer_identity(&req), Permission::Get, &registry, name).is_ok()
+    });
+    Ok(tide::Response::builder(200)
+        .body(descriptor::openapi(&services))
+        .build())
+}
+
 async fn get_observe(
     req: tide::Request<()>,
     sender: tide::sse::Sender,
 ) -> std::result::Result<(), tide::Error> {
     let bucket = req.param("bucket")?;
     let key = req.param("key")?;
-    let sender = Box::new(WebReceiver(sender));
+    let retain = req.url().query_pairs().any(|(name, _)| name == "retain");
+    let stream = Arc::new(WebStream::new(sender, retain));
+    let sender = Box::new(WebReceiver(Arc::clone(&stream)));
     get_event_broker()
         .alternative_register(sender, EventKey(bucket, key))
         .await;
+    if retain {
+        let snapshot = Registry::get_global()
+            .snapshot(web_peer(&req).as_ref(), &EventKey(bucket, key))
+            .await;
+        stream.release(snapshot).await;
+    }
     Ok(())
 }
 
@@ -140,24 +576,159 @@ async fn get_listen(
 ) -> std::result::Result<(), tide::Error> {
     let bucket = req.param("bucket")?;
     let key = req.param("key")?;
-    let sender = Box::new(WebReceiver(sender));
+    let sender = Box::new(WebReceiver(Arc::new(WebStream::new(sender, false))));
     get_notify_broker()
         .alternative_register(sender, EventKey(bucket, key))
         .await;
     Ok(())
 }
 
+async fn observe_endpoint(req: tide::Request<()>) -> tide::Result {
+    authorize(
+        &req,
+        &req.param("bucket")?,
+        &req.param("key")?,
+        Permission::Observe,
+    )?;
+    tide::sse::endpoint(get_observe).call(req).await
+}
+
+async fn listen_endpoint(req: tide::Request<()>) -> tide::Result {
+    authorize(
+        &req,
+        &req.param("bucket")?,
+        &req.param("key")?,
+        Permission::Listen,
+    )?;
+    tide::sse::endpoint(get_listen).call(req).await
+}
+
+async fn serve_connection<S>(
+    app: tide::Server<()>,
+    stream: S,
+    peer_addr: Option<String>,
+    peer: Option<String>,
+    ws_path: &str,
+) where
+    S: 'static + io::Read + io::Write + Clone + Unpin + Send + Sync,
+{
+    let (stream, upgrade) = match websocket::sniff(stream, ws_path).await {
+        Ok(ret) => ret,
+        Err(e) => {
+            log::debug!("http connection error: {}", e);
+            return;
+        }
+    };
+    if upgrade {
+        if let Err(e) = websocket::accept(stream, peer).await {
+            log::debug!("websocket connection error: {}", e);
+        }
+        return;
+    }
+    let ret = async_h1::accept(stream, |mut req| {
+        let app = app.clone();
+        let peer_addr = peer_addr.clone();
+        let peer = peer.clone();
+        async move {
+            req.set_peer_addr(peer_addr);
+            if let Some(peer) = peer {
+                req.ext_mut().insert(PeerIdentity(peer));
+            }
+            app.respond(req).await
+        }
+    })
+    .await;
+    if let Err(e) = ret {
+        log::debug!("http connection error: {}", e);
+    }
+}
+
+pub fn websocket_path(base: &str) -> String {
+    match base.trim_matches('/') {
+        "" => "/ws".to_owned(),
+        base => format!("/{}/ws", base),
+    }
+}
+
+pub async fn listen(
+    app: tide::Server<()>,
+    addr: String,
+    acceptor: Option<TlsAcceptor>,
+    ws_path: String,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        let app = app.clone();
+        let acceptor = acceptor.clone();
+        let ws_path = ws_path.clone();
+        task::spawn(async move {
+            let peer_addr = stream.peer_addr().ok().map(|x| x.to_string());
+            match acceptor {
+                Some(acceptor) => match tls::accept(&acceptor, stream).await {
+                    Ok((stream, peer)) => {
+                        serve_connection(app, stream, peer_addr, peer, &ws_path).await
+                    }
+                    Err(e) => log::debug!("tls handshake failed: {}", e),
+                },
+                None => serve_connection(app, stream, peer_addr, None, &ws_path).await,
+            }
+        });
+    }
+    Ok(())
+}
+
 pub fn init(route: &mut tide::Route<()>) {
     route.at("ping").get(|_| async { Ok("pong") });
-    route.at("map/:bucket").get(get_bucket);
-    route.at("map/:bucket/:key").get(get_bucket_key);
-    route.at("map/:bucket/:key").put(put_bucket_key);
-    route.at("map/:bucket/:key").delete(delete_bucket_key);
-    route.at("map/:bucket/:key").post(post_bucket_key);
+    let mut map = route.at("map");
+    map.with(ErrorBody);
+    map.at(":bucket").get(get_bucket);
+    map.at(":bucket/:key").get(get_bucket_key);
+    map.at(":bucket/:key").put(put_bucket_key);
+    map.at(":bucket/:key").patch(patch_bucket_key);
+    map.at(":bucket/:key").delete(delete_bucket_key);
+    map.at(":bucket/:key").post(post_bucket_key);
+    route
+        .at("registry/:name")
+        .with(ErrorBody)
+        .get(get_registry_name);
+    route.at("openapi.json").get(get_openapi);
     route
         .at("observe/:bucket/:key")
-        .get(tide::sse::endpoint(get_observe));
+        .with(ErrorBody)
+        .get(observe_endpoint);
     route
         .at("listen/:bucket/:key")
-        .get(tide::sse::endpoint(get_listen));
+        .with(ErrorBody)
+        .get(listen_endpoint);
+    route
+        .at("ws")
+        .get(|_| async { Ok(tide::Response::new(tide::StatusCode::UpgradeRequired)) });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn unread_call_stream_is_dropped() {
+        let (s, r) = CallReceiver::new();
+        s.call_next(0, b"a".to_vec()).await;
+        s.call_next(0, b"b".to_vec()).await;
+        let done = io::timeout(CALL_SEND_TIMEOUT / 10, async {
+            s.call_resp(0, ResponsePayload::Success).await;
+            Ok(())
+        });
+        assert!(done.await.is_ok());
+        let mut stream = CallStream {
+            _receiver: Arc::new(s),
+            events: r,
+            chunk: Vec::new(),
+            done: false,
+        };
+        let mut body = Vec::new();
+        assert!(stream.read_to_end(&mut body).await.is_err());
+        assert_eq!(body, b"a");
+    }
 }
@@ -0,0 +1,255 @@
+use crate::gateway::handle_connection;
+use anyhow::Result;
+use async_std::io::{self, Read, Write};
+use async_std::prelude::*;
+use async_tungstenite::tungstenite::{Error as WsError, Message};
+use async_tungstenite::WebSocketStream;
+use futures_util::sink::Sink;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::StreamExt;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+const MAX_HEAD: usize = 8192;
+
+// replays the sniffed request head before reading from the underlying stream
+#[derive(Clone)]
+pub struct Prefixed<S> {
+    head: Arc<Mutex<Cursor<Vec<u8>>>>,
+    inner: S,
+}
+
+impl<S: Read + Unpin> Read for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        {
+            let mut head = self.head.lock().unwrap();
+            if (head.position() as usize) < head.get_ref().len() {
+                return Poll::Ready(std::io::Read::read(&mut *head, buf));
+            }
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: Write + Unpin> Write for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+fn is_upgrade(head: &[u8], path: &str) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let mut request = lines.next().unwrap_or_default().split(' ');
+    if request.next() != Some("GET") {
+        return false;
+    }
+    match request.next().and_then(|x| x.split('?').next()) {
+        Some(target) if target == path => {}
+        _ => return false,
+    }
+    lines
+        .take_while(|x| !x.is_empty())
+        .filter_map(|x| x.split_once(':'))
+        .any(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.trim().eq_ignore_ascii_case("websocket")
+        })
+}
+
+pub async fn sniff<S: Read + Unpin>(mut stream: S, path: &str) -> io::Result<(Prefixed<S>, bool)> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while head.len() < MAX_HEAD && !head.windows(4).any(|x| x == b"\r\n\r\n") {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..len]);
+    }
+    let upgrade = is_upgrade(&head, path);
+    Ok((
+        Prefixed {
+            head: Arc::new(Mutex::new(Cursor::new(head))),
+            inner: stream,
+        },
+        upgrade,
+    ))
+}
+
+fn to_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+// each binary message carries a chunk of the same byte stream as the tcp gateway
+pub struct WsReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S: Read + Write + Unpin> Read for WsReader<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.pos >= this.buf.len() {
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    this.buf = data;
+                    this.pos = 0;
+                }
+                Poll::Ready(Some(Ok(Message::Text(_)))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text messages are not supported",
+                    )))
+                }
+                Poll::Ready(Some(Ok(Message::Close(_)))) | Poll::Ready(None) => {
+                    return Poll::Ready(Ok(0))
+                }
+                Poll::Ready(Some(Ok(_))) => {}
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+        let len = buf.len().min(this.buf.len() - this.pos);
+        buf[..len].copy_from_slice(&this.buf[this.pos..this.pos + len]);
+        this.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+// buffers writes and sends them as a single binary message on flush
+pub struct WsWriter<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+    buf: Vec<u8>,
+}
+
+impl<S: Read + Write + Unpin> Write for WsWriter<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.buf.is_empty() {
+            match Pin::new(&mut this.sink).poll_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+                Poll::Ready(Ok(())) => {}
+            }
+            let data = std::mem::take(&mut this.buf);
+            if let Err(e) = Pin::new(&mut this.sink).start_send(Message::Binary(data)) {
+                return Poll::Ready(Err(to_io_error(e)));
+            }
+        }
+        Pin::new(&mut this.sink).poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut self.sink).poll_close(cx).map_err(to_io_error)
+    }
+}
+
+pub async fn accept<S>(stream: S, peer: Option<String>) -> Result<()>
+where
+    S: 'static + Read + Write + Unpin + Send,
+{
+    let (sink, stream) = async_tungstenite::accept_async(stream).await?.split();
+    let reader = WsReader {
+        stream,
+        buf: Vec::new(),
+        pos: 0,
+    };
+    let writer = WsWriter {
+        sink,
+        buf: Vec::new(),
+    };
+    handle_connection(reader, writer, peer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPGRADE: &[u8] = b"GET /ws?token=1 HTTP/1.1\r\nHost: x\r\nUPGRADE:  WebSocket \r\n\r\n";
+
+    #[test]
+    fn detect_upgrade() {
+        assert!(is_upgrade(UPGRADE, "/ws"));
+        assert!(is_upgrade(
+            b"GET /ws HTTP/1.1\r\nupgrade: websocket\r\n\r\n",
+            "/ws"
+        ));
+        assert!(!is_upgrade(UPGRADE, "/other"));
+        assert!(!is_upgrade(
+            b"POST /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n",
+            "/ws"
+        ));
+        assert!(!is_upgrade(
+            b"GET /ws HTTP/1.1\r\nUpgrade: h2c\r\n\r\n",
+            "/ws"
+        ));
+        assert!(!is_upgrade(
+            b"GET /ws HTTP/1.1\r\n\r\nUpgrade: websocket\r\n",
+            "/ws"
+        ));
+        assert!(!is_upgrade(b"MINIBUS\x01\x02\x00", "/ws"));
+    }
+
+    #[async_std::test]
+    async fn sniff_replays_head() {
+        let mut input = UPGRADE.to_vec();
+        input.extend_from_slice(b"frames");
+        let (mut stream, upgrade) = sniff(input.as_slice(), "/ws").await.unwrap();
+        assert!(upgrade);
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[async_std::test]
+    async fn sniff_stops_at_max_head() {
+        let mut input = b"GET /ws HTTP/1.1\r\nX: ".to_vec();
+        input.resize(MAX_HEAD * 2, b'a');
+        input.extend_from_slice(b"\r\nUpgrade: websocket\r\n\r\n");
+        let (mut stream, upgrade) = sniff(input.as_slice(), "/ws").await.unwrap();
+        assert!(!upgrade);
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, input);
+    }
+}